@@ -0,0 +1,184 @@
+use std::fs::{create_dir_all, remove_dir, rename, symlink_metadata, write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::on,
+    fs::{absolute_path, dot_entry, is_empty, source_dir, FOLD_FILE},
+    install::install_entry,
+    journal::{self, Action},
+    manifest, Context, Error,
+};
+
+pub enum Label {
+    Machine,
+    Platform,
+}
+
+pub fn run(ctx: &Context, path: &str, label: Option<Label>) -> Result<(), Error> {
+    let dot_file = match absolute_dot_file(ctx, path) {
+        Some(p) => p,
+        None => {
+            ctx.message()
+                .with_verb("skipping")
+                .warning(Some(format!("{} is not a dot file in your home", path)));
+            return Ok(());
+        }
+    };
+
+    if dot_file.is_symlink() {
+        ctx.message()
+            .with_verb("skipping")
+            .with_path(&dot_file)
+            .warning(Some(String::from("already a link")));
+        return Ok(());
+    }
+
+    let relative = dot_file
+        .strip_prefix(&ctx.home)
+        .ok()
+        .and_then(|p| p.to_str())
+        .and_then(|p| p.strip_prefix(ctx.config.dot()))
+        .map(PathBuf::from)
+        .expect("already checked this was a dot file");
+
+    let source = match with_label(ctx, &relative, label) {
+        Some(labeled) => source_dir(ctx).join(labeled),
+        None => {
+            ctx.message()
+                .with_verb("skipping")
+                .with_path(&dot_file)
+                .warning(Some(String::from("has no file name to label")));
+            return Ok(());
+        }
+    };
+    let shown_source = source
+        .strip_prefix(&ctx.root)
+        .unwrap_or(&source)
+        .display()
+        .to_string();
+
+    if symlink_metadata(&source).is_ok() {
+        ctx.message()
+            .with_verb("skipping")
+            .with_path(&dot_file)
+            .warning(Some(format!("{} already exists", shown_source)));
+        return Ok(());
+    }
+
+    ctx.message()
+        .with_verb(if ctx.dry_run {
+            "would adopt"
+        } else {
+            "adopting"
+        })
+        .with_path(&dot_file)
+        .success(Some(shown_source));
+
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    let parent = source.parent().expect("Why is there no parent?");
+    create_dir_all(parent).map_err(on(parent))?;
+    rename(&dot_file, &source).map_err(on(&dot_file))?;
+    journal::record(
+        ctx,
+        Action::Rename {
+            from: dot_file,
+            to: source.clone(),
+        },
+    )?;
+
+    // a directory is linked as a whole, cleanup would take a link to an
+    // unfolded one for a leftover
+    if source.is_dir() {
+        let marker = source.join(FOLD_FILE);
+        write(&marker, "").map_err(on(&marker))?;
+    }
+
+    if let Some(entry) = dot_entry(ctx, &source)? {
+        install_entry(ctx, entry)?;
+    }
+
+    manifest::save(ctx)
+}
+
+// Moves everything in a real directory in ~ into the repo directory it
+// should have been a link to, leaving anything the repo already has where
+// it is, and removes the directory if that emptied it
+pub fn merge_dir(ctx: &Context, dir: &Path, into: &Path) -> Result<(), Error> {
+    for entry in dir.read_dir().map_err(on(dir))? {
+        let path = entry.map_err(on(dir))?.path();
+        let dest = into.join(path.file_name().expect("read_dir gives names"));
+
+        if !path.is_symlink() && path.is_dir() && dest.is_dir() {
+            merge_dir(ctx, &path, &dest)?;
+        } else if symlink_metadata(&dest).is_ok() {
+            ctx.message()
+                .with_verb("keeping")
+                .with_path(&path)
+                .warning(Some(String::from("the repo already has one")));
+        } else {
+            ctx.message()
+                .with_verb(if ctx.dry_run {
+                    "would merge"
+                } else {
+                    "merging"
+                })
+                .with_path(&path)
+                .with_target_shown(&dest)
+                .success(None);
+
+            if !ctx.dry_run {
+                rename(&path, &dest).map_err(on(&path))?;
+                journal::record(
+                    ctx,
+                    Action::Rename {
+                        from: path,
+                        to: dest,
+                    },
+                )?;
+            }
+        }
+    }
+
+    if !ctx.dry_run && is_empty(dir) {
+        remove_dir(dir).map_err(on(dir))?;
+        journal::record(
+            ctx,
+            Action::RemoveDir {
+                dir: dir.to_path_buf(),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+fn absolute_dot_file(ctx: &Context, path: &str) -> Option<PathBuf> {
+    let dot_file = absolute_path(path)?;
+
+    // without a dot prefix everything starts with it, home itself included
+    let relative = dot_file.strip_prefix(&ctx.home).ok()?.to_str()?;
+
+    (!relative.is_empty() && relative.starts_with(ctx.config.dot())).then_some(dot_file)
+}
+
+fn with_label(ctx: &Context, relative: &Path, label: Option<Label>) -> Option<PathBuf> {
+    let this = &ctx.this;
+
+    let (link_side, value) = match label {
+        Some(Label::Machine) => ("_machine", &this.machine),
+        Some(Label::Platform) => ("_platform", &this.platform),
+        None => return Some(relative.to_path_buf()),
+    };
+
+    let file_name = relative.file_name()?.to_str()?;
+
+    Some(
+        relative.with_file_name(match file_name.strip_prefix(link_side) {
+            Some(rest) => format!("_{}{}", value, rest),
+            None => format!("_{}.{}", value, file_name),
+        }),
+    )
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::{error::on, Error};
+
+pub const QUESTIONS: [&str; 6] = ["replace", "backup", "merge", "pull", "delete", "rmdir"];
+
+// Canned answers for prompts, so a run doesn't need anyone at the keyboard
+#[derive(Debug, Default)]
+pub struct Answers {
+    rules: HashMap<String, char>,
+    fallback: Option<char>,
+}
+
+impl Answers {
+    pub fn always(answer: char) -> Answers {
+        Answers {
+            rules: HashMap::new(),
+            fallback: Some(answer),
+        }
+    }
+
+    // one `question = answer` per line, `*` answers anything not listed
+    pub fn from_file(path: &Path, fallback: Option<char>) -> Result<Answers, Error> {
+        let contents = read_to_string(path).map_err(on(path))?;
+        let mut answers = Answers {
+            rules: HashMap::new(),
+            fallback,
+        };
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let bad_line = |message: &str| Error::BadConfig {
+                path: path.to_path_buf(),
+                message: format!("line {}: {}", number + 1, message),
+            };
+
+            let (question, answer) = line
+                .split_once('=')
+                .map(|(q, a)| (q.trim(), a.trim()))
+                .ok_or_else(|| bad_line("expected question = answer"))?;
+
+            let answer = parse_answer(answer).ok_or_else(|| bad_line("answer must be y or n"))?;
+
+            if question == "*" {
+                answers.fallback = Some(answer);
+            } else if QUESTIONS.contains(&question) {
+                answers.rules.insert(question.to_string(), answer);
+            } else {
+                return Err(bad_line(&format!(
+                    "unknown question, expected one of {} or *",
+                    QUESTIONS.join(" ")
+                )));
+            }
+        }
+
+        Ok(answers)
+    }
+
+    pub fn answer_for(&self, question: &str) -> Option<char> {
+        self.rules.get(question).copied().or(self.fallback)
+    }
+
+    // only what was asked for by name, --yes shouldn't move files into
+    // the repo
+    pub fn rule_for(&self, question: &str) -> Option<char> {
+        self.rules.get(question).copied()
+    }
+}
+
+fn parse_answer(answer: &str) -> Option<char> {
+    match answer.to_lowercase().as_str() {
+        "y" | "yes" => Some('y'),
+        "n" | "no" => Some('n'),
+        _ => None,
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs::{create_dir_all, remove_dir, remove_file, rename};
+use std::path::{Component, Path, PathBuf};
+
+use crate::{
+    error::on,
+    fs::{absolute_path, is_empty, resolve_link},
+    journal::{self, Action},
+    manifest, Context, Error,
+};
+
+const BACKUPS_DIR: &str = "backups";
+const COPIES_DIR: &str = "copies";
+
+fn backups_dir(ctx: &Context) -> PathBuf {
+    ctx.state_dir.join(BACKUPS_DIR)
+}
+
+// Moves a real file or directory out of the way to
+// `<state>/backups/<run id>/<path inside ~>` and returns where it went
+pub fn back_up(ctx: &Context, path: &Path) -> Result<PathBuf, Error> {
+    move_for_run(ctx, &backups_dir(ctx), path)
+}
+
+// An unedited copy being replaced or removed isn't a backup, but undo
+// needs it to put back what was there, so it goes to `<state>/copies`
+pub fn keep_copy(ctx: &Context, path: &Path) -> Result<PathBuf, Error> {
+    move_for_run(ctx, &ctx.state_dir.join(COPIES_DIR), path)
+}
+
+fn move_for_run(ctx: &Context, dir: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let relative: PathBuf = path
+        .strip_prefix(&ctx.home)
+        .unwrap_or(path)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let backup = dir.join(ctx.journal.run_id()).join(relative);
+
+    let parent = backup.parent().expect("backups live in a directory");
+    create_dir_all(parent).map_err(on(parent))?;
+    rename(path, &backup).map_err(on(path))?;
+
+    Ok(backup)
+}
+
+struct Backup {
+    run_id: String,
+    original: PathBuf,
+    backup: PathBuf,
+}
+
+// the journal knows what was moved where, and whatever is still there
+// hasn't been restored or undone
+fn backups(ctx: &Context) -> Result<Vec<Backup>, Error> {
+    let dir = backups_dir(ctx);
+
+    Ok(journal::runs(ctx)?
+        .into_iter()
+        .flat_map(|(run_id, actions)| {
+            actions.into_iter().filter_map(move |action| match action {
+                Action::Rename { from, to } => Some(Backup {
+                    run_id: run_id.clone(),
+                    original: from,
+                    backup: to,
+                }),
+                _ => None,
+            })
+        })
+        .filter(|b| b.backup.starts_with(&dir) && b.backup.symlink_metadata().is_ok())
+        .collect())
+}
+
+pub fn list(ctx: &Context) -> Result<(), Error> {
+    let backups = backups(ctx)?;
+
+    if backups.is_empty() {
+        ctx.message()
+            .with_verb("backups")
+            .log(Some(String::from("nothing backed up")));
+    }
+
+    for backup in backups {
+        ctx.message()
+            .with_verb("backed up")
+            .with_path(&backup.original)
+            .with_target_shown(&backup.backup)
+            .log(Some(backup.run_id));
+    }
+
+    Ok(())
+}
+
+// Puts back everything from runs whose id starts with run_id, or just
+// the one file or directory, replacing a link of ours if there is one
+pub fn restore(ctx: &Context, run_id: &str, path: Option<&str>) -> Result<(), Error> {
+    let path = path.map(|path| absolute_path(path).unwrap_or_else(|| PathBuf::from(path)));
+
+    let chosen: Vec<Backup> = backups(ctx)?
+        .into_iter()
+        .filter(|b| b.run_id.starts_with(run_id))
+        .filter(|b| path.as_ref().is_none_or(|path| &b.original == path))
+        .collect();
+
+    if chosen.is_empty() {
+        ctx.message()
+            .with_verb("restore")
+            .warning(Some(format!("no backups from {} to restore", run_id)));
+        return Ok(());
+    }
+
+    for backup in chosen {
+        restore_one(ctx, &backup)?;
+    }
+
+    manifest::save(ctx)
+}
+
+fn restore_one(ctx: &Context, backup: &Backup) -> Result<(), Error> {
+    let original = &backup.original;
+    let ours = original.is_symlink()
+        && resolve_link(original).is_ok_and(|target| {
+            target.starts_with(&ctx.root) || manifest::is_recorded_link(ctx, original, &target)
+        });
+
+    if !ours && original.symlink_metadata().is_ok() {
+        ctx.message()
+            .with_verb("skipping")
+            .with_path(original)
+            .warning(Some(String::from("something else is there now")));
+        return Ok(());
+    }
+
+    ctx.message()
+        .with_verb(if ctx.dry_run {
+            "would restore"
+        } else {
+            "restoring"
+        })
+        .with_path(original)
+        .with_target_shown(&backup.backup)
+        .success(None);
+
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    if ours {
+        let target = resolve_link(original).map_err(on(original))?;
+        remove_file(original).map_err(on(original))?;
+        manifest::forget(ctx, original);
+        journal::record(
+            ctx,
+            Action::Unlink {
+                link: original.clone(),
+                target,
+            },
+        )?;
+    }
+
+    let parent = original.parent().expect("What is this at the root?");
+    create_dir_all(parent).map_err(on(parent))?;
+    rename(&backup.backup, original).map_err(on(&backup.backup))?;
+    journal::record(
+        ctx,
+        Action::Rename {
+            from: backup.backup.clone(),
+            to: original.clone(),
+        },
+    )?;
+
+    // don't leave the run's empty directories behind
+    let dir = backups_dir(ctx);
+    for parent in backup.backup.ancestors().skip(1) {
+        if parent == dir || !is_empty(parent) {
+            break;
+        }
+        remove_dir(parent).map_err(on(parent))?;
+    }
+
+    Ok(())
+}
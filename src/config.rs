@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{canonicalize, read_to_string};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use globset::{Glob, GlobMatcher, GlobSet, GlobSetBuilder};
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+
+use crate::{error::on, fs::Strategy, Error};
+
+pub const CONFIG_FILE: &str = "link_o_matic.toml";
+
+// Everything LINKOMATIC_ROOT/link_o_matic.toml can change, all optional
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    source: String,
+    dot_prefix: bool,
+    #[serde(deserialize_with = "in_file_order")]
+    labels: Vec<(String, Vec<String>)>,
+    ignore: Vec<String>,
+    fold: Vec<String>,
+    strategies: BTreeMap<String, String>,
+    allow_outside: Vec<String>,
+    relative_links: bool,
+}
+
+impl Default for RawConfig {
+    fn default() -> Self {
+        RawConfig {
+            source: String::from("home"),
+            dot_prefix: true,
+            labels: vec![],
+            ignore: vec![],
+            fold: vec![],
+            strategies: BTreeMap::new(),
+            allow_outside: vec![],
+            relative_links: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub source: String,
+    pub dot_prefix: bool,
+    // in the order they're written, earlier labels win over later ones
+    pub labels: Vec<(String, Vec<String>)>,
+    pub relative_links: bool,
+    ignore: GlobSet,
+    fold: GlobSet,
+    strategies: Vec<(GlobMatcher, Strategy)>,
+    allow_outside: Vec<PathBuf>,
+}
+
+impl Config {
+    pub fn load(root: &Path) -> Result<Config, Error> {
+        let path = root.join(CONFIG_FILE);
+
+        let raw = match read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| Error::BadConfig {
+                path: path.clone(),
+                message: match e.span() {
+                    Some(span) => format!(
+                        "line {}: {}",
+                        contents[..span.start].matches('\n').count() + 1,
+                        e.message()
+                    ),
+                    None => e.message().to_string(),
+                },
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => RawConfig::default(),
+            Err(e) => return Err(on(&path)(e)),
+        };
+
+        Config::compile(&path, raw)
+    }
+
+    fn compile(path: &Path, raw: RawConfig) -> Result<Config, Error> {
+        let bad_config = |message: String| Error::BadConfig {
+            path: path.to_path_buf(),
+            message,
+        };
+        let glob = |pattern: &str| {
+            Glob::new(pattern).map_err(|e| bad_config(format!("bad pattern {}: {}", pattern, e)))
+        };
+
+        // somewhere below the root, the root itself would link the config
+        let source = Path::new(&raw.source);
+        if source.is_absolute()
+            || source
+                .components()
+                .any(|component| component == Component::ParentDir)
+            || source
+                .components()
+                .all(|component| component == Component::CurDir)
+        {
+            return Err(bad_config(String::from(
+                "source must be a directory inside LINKOMATIC_ROOT",
+            )));
+        }
+
+        let mut ignore = GlobSetBuilder::new();
+        for pattern in &raw.ignore {
+            ignore.add(glob(pattern)?);
+        }
+
+        let mut fold = GlobSetBuilder::new();
+        for pattern in &raw.fold {
+            fold.add(glob(pattern)?);
+        }
+
+        let mut strategies = vec![];
+        for (pattern, name) in &raw.strategies {
+            let strategy = Strategy::parse(name).ok_or_else(|| {
+                bad_config(format!(
+                    "unknown strategy {} for {}, expected link, copy or template",
+                    name, pattern
+                ))
+            })?;
+            strategies.push((glob(pattern)?.compile_matcher(), strategy));
+        }
+
+        if let Some(dir) = raw
+            .allow_outside
+            .iter()
+            .find(|dir| !Path::new(dir).is_absolute())
+        {
+            return Err(bad_config(format!(
+                "allow_outside must be absolute paths, not {}",
+                dir
+            )));
+        }
+
+        Ok(Config {
+            source: raw.source,
+            dot_prefix: raw.dot_prefix,
+            labels: raw.labels,
+            relative_links: raw.relative_links,
+            ignore: ignore
+                .build()
+                .map_err(|e| bad_config(format!("bad ignore patterns: {}", e)))?,
+            fold: fold
+                .build()
+                .map_err(|e| bad_config(format!("bad fold patterns: {}", e)))?,
+            strategies,
+            allow_outside: raw.allow_outside.into_iter().map(PathBuf::from).collect(),
+        })
+    }
+
+    // what goes in front of a link name in ~
+    pub fn dot(&self) -> &'static str {
+        if self.dot_prefix {
+            "."
+        } else {
+            ""
+        }
+    }
+
+    // relative to the source directory, a directory ignores all inside it
+    pub fn is_ignored(&self, relative: &Path) -> bool {
+        relative
+            .ancestors()
+            .take_while(|p| !p.as_os_str().is_empty())
+            .any(|p| self.ignore.is_match(p))
+    }
+
+    pub fn is_folded(&self, relative: &Path) -> bool {
+        self.fold.is_match(relative)
+    }
+
+    pub fn strategy_for(&self, relative: &Path) -> Option<Strategy> {
+        self.strategies
+            .iter()
+            .find(|(matcher, _)| matcher.is_match(relative))
+            .map(|(_, strategy)| *strategy)
+    }
+
+    // an alias may point outside LINKOMATIC_ROOT only into these
+    pub fn allows_outside(&self, target: &Path) -> bool {
+        self.allow_outside
+            .iter()
+            .any(|dir| canonicalize(dir).is_ok_and(|dir| target.starts_with(dir)))
+    }
+
+    // labels whose list of machines includes this one
+    pub fn labels_for(&self, machine: &str) -> Vec<String> {
+        self.labels
+            .iter()
+            .filter(|(_, machines)| machines.iter().any(|m| m == machine))
+            .map(|(label, _)| label.clone())
+            .collect()
+    }
+}
+
+// a table's keys as they come in the file instead of sorted
+fn in_file_order<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, Vec<String>)>, D::Error> {
+    struct InFileOrder;
+
+    impl<'de> Visitor<'de> for InFileOrder {
+        type Value = Vec<(String, Vec<String>)>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a table of label = [machines]")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut labels = vec![];
+            while let Some(label) = map.next_entry()? {
+                labels.push(label);
+            }
+            Ok(labels)
+        }
+    }
+
+    deserializer.deserialize_map(InFileOrder)
+}
@@ -0,0 +1,260 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{canonicalize, read_to_string};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::{
+    answers::Answers,
+    config::Config,
+    ignores::Ignores,
+    journal::Journal,
+    manifest::Manifest,
+    messages::{Format, MessageBuilder, Messenger},
+    plan::Plan,
+    template, Error,
+};
+
+// A source file starting with `_default` is linked as `_machine` when
+// nothing more specific to this machine claims that link
+pub const DEFAULT_LABEL: &str = "default";
+
+// What this machine is, for picking which labeled files get linked
+#[derive(Debug, Default)]
+pub struct This {
+    pub platform: String,
+    pub machine: String,
+    pub arch: String,
+    pub distro: Option<String>,
+    pub wsl: bool,
+    pub tags: Vec<String>,
+}
+
+// A source file starting with `_<value>` is linked as `_<link_side>`
+#[derive(Debug)]
+pub struct Selector<'a> {
+    pub value: &'a str,
+    pub link_side: &'a str,
+}
+
+impl This {
+    // machine last, it's the most specific, then the default standing in
+    // for it
+    pub fn selectors(&self) -> Vec<Selector<'_>> {
+        let mut selectors = vec![
+            Selector {
+                value: &self.platform,
+                link_side: "platform",
+            },
+            Selector {
+                value: &self.arch,
+                link_side: "arch",
+            },
+        ];
+
+        if let Some(distro) = &self.distro {
+            selectors.push(Selector {
+                value: distro,
+                link_side: "distro",
+            });
+        }
+
+        if self.wsl {
+            selectors.push(Selector {
+                value: "wsl",
+                link_side: "wsl",
+            });
+        }
+
+        // tags are their own link side
+        selectors.extend(self.tags.iter().map(|tag| Selector {
+            value: tag,
+            link_side: tag,
+        }));
+
+        selectors.push(Selector {
+            value: &self.machine,
+            link_side: "machine",
+        });
+
+        selectors.push(Selector {
+            value: DEFAULT_LABEL,
+            link_side: "machine",
+        });
+
+        selectors
+    }
+}
+
+// Where we are linking from and to, and everything a single run needs to
+// remember along the way
+pub struct Context {
+    pub home: PathBuf,
+    pub root: PathBuf,
+    pub state_dir: PathBuf,
+    pub this: This,
+    pub config: Config,
+    pub ignores: Ignores,
+    pub vars: BTreeMap<String, String>,
+    pub dry_run: bool,
+    pub trash: Option<PathBuf>,
+    pub format: Format,
+    pub delete_all: Cell<bool>,
+    pub answers: Answers,
+    pub manifest: RefCell<Manifest>,
+    pub journal: Journal,
+    pub plan: RefCell<Option<Rc<Plan>>>,
+    pub input: RefCell<Box<dyn BufRead>>,
+    messenger: Messenger,
+}
+
+#[derive(Debug, Default)]
+pub struct ContextOptions {
+    pub answers: Answers,
+    pub home: Option<String>,
+    pub root: Option<String>,
+    pub hostname: Option<String>,
+    pub tags: Option<String>,
+    pub dry_run: bool,
+    pub trash: bool,
+    pub format: Format,
+}
+
+impl Context {
+    pub fn new(options: ContextOptions, input: Box<dyn BufRead>) -> Result<Context, Error> {
+        let home = canonicalize_path("HOME", or_env(options.home.clone(), "HOME")?)?;
+        let root = canonicalize_path("LINKOMATIC_ROOT", or_env(options.root, "LINKOMATIC_ROOT")?)?;
+        let machine = or_env(options.hostname, "LINKOMATIC_HOSTNAME")?;
+
+        let platform = match std::env::consts::OS {
+            "linux" => "linux",
+            "macos" => "mac",
+            _ => "unknown",
+        }
+        .into();
+
+        // a staging home keeps its own state and trash instead of the
+        // running user's
+        let staging = options.home.is_some();
+        let state_dir =
+            xdg_dir(staging, "XDG_STATE_HOME", &home, ".local/state").join("link_o_matic");
+        let trash = options
+            .trash
+            .then(|| xdg_dir(staging, "XDG_DATA_HOME", &home, ".local/share").join("Trash"));
+
+        let config = Config::load(&root)?;
+
+        // in the order given, it's the order they win in
+        let mut tags: Vec<String> = vec![];
+        for tag in options
+            .tags
+            .or_else(|| env::var("LINKOMATIC_TAGS").ok())
+            .unwrap_or_default()
+            .split([',', ' '])
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .chain(config.labels_for(&machine))
+        {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        let ignores = Ignores::load(&root, &root.join(&config.source))?;
+        let vars = template::load_vars(&root.join(template::VARS_DIR).join(&machine))?;
+
+        Ok(Context {
+            manifest: RefCell::new(Manifest::load(&state_dir)),
+            journal: Journal::new(&state_dir),
+            plan: RefCell::new(None),
+            messenger: Messenger::new(Some(home.clone()), options.format),
+            home,
+            root,
+            state_dir,
+            this: This {
+                platform,
+                machine,
+                arch: env::consts::ARCH.into(),
+                distro: distro(),
+                wsl: is_wsl(),
+                tags,
+            },
+            config,
+            ignores,
+            vars,
+            dry_run: options.dry_run,
+            trash,
+            format: options.format,
+            delete_all: Cell::new(false),
+            answers: options.answers,
+            input: RefCell::new(input),
+        })
+    }
+
+    pub fn message(&self) -> MessageBuilder<'_> {
+        self.messenger.message()
+    }
+
+    // a message that couldn't be written, once the run is over
+    pub fn output_failure(&self) -> Option<Error> {
+        self.messenger.take_failure()
+    }
+}
+
+fn or_env(value: Option<String>, name: &str) -> Result<String, Error> {
+    match value {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => get_env(name),
+    }
+}
+
+fn get_env(name: &str) -> Result<String, Error> {
+    env::var(name)
+        .and_then(|value| {
+            if value.is_empty() {
+                Err(env::VarError::NotPresent)
+            } else {
+                Ok(value)
+            }
+        })
+        .map_err(|source| Error::MissingEnv {
+            name: name.to_string(),
+            source,
+        })
+}
+
+fn xdg_dir(staging: bool, name: &str, home: &Path, default: &str) -> PathBuf {
+    match staging {
+        true => None,
+        false => env::var(name)
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from),
+    }
+    .unwrap_or_else(|| home.join(default))
+}
+
+fn canonicalize_path(name: &str, path: String) -> Result<PathBuf, Error> {
+    canonicalize(path).map_err(|source| Error::BadPath {
+        name: name.to_string(),
+        source,
+    })
+}
+
+// the ID from /etc/os-release, like ubuntu or fedora
+fn distro() -> Option<String> {
+    read_to_string("/etc/os-release")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches(['"', '\'']).to_string())
+        .filter(|id| !id.is_empty())
+}
+
+fn is_wsl() -> bool {
+    env::var_os("WSL_DISTRO_NAME").is_some()
+        || read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+}
@@ -1,15 +1,20 @@
-use std::error::Error;
-use std::fs::{metadata, remove_dir, remove_file, rename};
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{canonicalize, metadata, remove_dir, remove_file};
+use std::path::{Path, PathBuf};
 
 use crate::{
+    backup,
+    error::on,
     fs::{
         find_links_to_targets, has_bad_underscore, has_no_matching_target,
-        home_path_starts_with_dot, is_empty, is_invalid_to_target, name_with_bak, DotEntry,
+        home_path_starts_with_dot, is_empty, is_ignored_link, is_invalid_to_target, is_stale_fold,
+        resolve_link, DotEntry, Strategy,
     },
-    get_delete_all, get_root,
-    messages::display_delete_prompt,
-    Messenger,
+    journal::{self, Action},
+    manifest,
+    messages::{conjugate_with, display_delete_prompt, log_skipping},
+    plan, trash, Context, Error,
 };
 
 #[derive(Debug, Default)]
@@ -17,116 +22,257 @@ pub struct DeleteOptions<'a> {
     pub implode: bool,
     pub without_prompting: bool,
     pub verb_template: &'a str,
+    pub question: &'a str,
+    // a real directory can be merged into the repo instead
+    pub can_merge: bool,
+    // a real file can be pulled into the repo instead
+    pub can_pull: bool,
+    // a real file can be compared with what would replace it
+    pub diff_with: Option<&'a DotEntry>,
 }
 
-pub fn run(implode: bool, without_prompting: bool) -> Result<(), Box<dyn Error>> {
+pub fn run(ctx: &Context, implode: bool, without_prompting: bool) -> Result<(), Error> {
+    // collisions would otherwise only turn up partway through
+    if !implode {
+        plan::get(ctx)?;
+    }
+
     if without_prompting {
-        let mut delete_all = get_delete_all().lock().expect("How did I break mutex");
-        *delete_all = true;
+        ctx.delete_all.set(true);
     };
 
     let delete_options = &DeleteOptions {
         implode,
         without_prompting,
-        verb_template: &"delet%",
+        verb_template: "delet%",
+        question: "delete",
+        can_merge: false,
+        can_pull: false,
+        diff_with: None,
     };
 
     let dir_delete_options = &DeleteOptions {
         implode: false,
         without_prompting,
-        verb_template: &"remov% empty directory",
+        verb_template: "remov% empty directory",
+        question: "rmdir",
+        can_merge: false,
+        can_pull: false,
+        diff_with: None,
     };
 
-    let root = get_root();
+    let root = &ctx.root;
+    let seen = RefCell::new(HashSet::new());
 
-    let handle_delete = &|link: PathBuf| -> Result<(), Box<dyn Error>> {
-        if link.is_symlink() && home_path_starts_with_dot(&link) {
-            let target = link.read_link().expect("is_symlink, what gives?");
+    let delete_entry = &|entry: DotEntry| -> Result<(), Error> {
+        if decide_delete(ctx, &entry, delete_options)? {
+            let parent = &entry
+                .link
+                .parent()
+                .map(|p| p.to_path_buf())
+                .expect("why is there no parent?");
 
-            if target.starts_with(root) {
-                let entry = DotEntry {
-                    link: link.to_path_buf(),
-                    target,
+            if is_empty(parent) && delete_prompt(ctx, parent, dir_delete_options)? && !ctx.dry_run {
+                let action = match &ctx.trash {
+                    Some(trash) => Action::Trash {
+                        path: parent.to_path_buf(),
+                        trashed: trash::put(trash, parent)?,
+                    },
+                    None => {
+                        remove_dir(parent).map_err(on(parent))?;
+                        Action::RemoveDir {
+                            dir: parent.to_path_buf(),
+                        }
+                    }
                 };
+                journal::record(ctx, action)?;
+            }
+        }
+        Ok(())
+    };
 
-                if decide_delete(&entry, delete_options) {
-                    let parent = &entry
-                        .link
-                        .parent()
-                        .map(|p| p.to_path_buf())
-                        .expect("why is there no parent?");
+    let handle_delete = &|link: PathBuf| -> Result<(), Error> {
+        if !seen.borrow_mut().insert(link.clone()) {
+            return Ok(());
+        }
 
-                    if is_empty(&parent) {
-                        delete_prompt(&parent, dir_delete_options);
-                    }
-                }
+        // reached through a link of ours, this is the repo's own
+        if link
+            .parent()
+            .and_then(|parent| canonicalize(parent).ok())
+            .is_some_and(|parent| parent.starts_with(root))
+        {
+            return Ok(());
+        }
+
+        if link.is_symlink() && home_path_starts_with_dot(ctx, &link) {
+            let target = resolve_link(&link).map_err(on(&link))?;
+
+            if target.starts_with(root) || manifest::is_recorded_link(ctx, &link, &target) {
+                delete_entry(DotEntry {
+                    link: link.to_path_buf(),
+                    target,
+                    strategy: Strategy::Link,
+                })?;
             }
         }
         Ok(())
     };
 
-    find_links_to_targets(&handle_delete)?;
+    find_links_to_targets(ctx, &handle_delete)?;
 
-    Ok(())
+    // links made by install whose source directory no longer exists
+    // are only known to the manifest, and copies only ever are
+    for (link, managed) in manifest::links(ctx) {
+        if link.is_symlink() {
+            handle_delete(link)?;
+        } else if managed.hash.is_some() && link.is_file() {
+            delete_entry(DotEntry {
+                link,
+                target: managed.target,
+                strategy: managed.strategy,
+            })?;
+        } else {
+            manifest::forget(ctx, &link);
+        }
+    }
+
+    manifest::save(ctx)
 }
 
-pub fn decide_delete(entry: &DotEntry, delete_options: &DeleteOptions) -> bool {
-    if delete_options.implode
-        || is_invalid_to_target(&entry.target)
-        || has_bad_underscore(&entry.link)
+pub fn decide_delete(
+    ctx: &Context,
+    entry: &DotEntry,
+    delete_options: &DeleteOptions,
+) -> Result<bool, Error> {
+    let with_diff;
+    let delete_options =
+        if !entry.link.is_symlink() && entry.link.is_file() && entry.target.is_file() {
+            with_diff = DeleteOptions {
+                diff_with: Some(entry),
+                ..*delete_options
+            };
+            &with_diff
+        } else {
+            delete_options
+        };
+
+    if (delete_options.implode
+        || is_invalid_to_target(ctx, &entry.target)
+        || has_bad_underscore(ctx, &entry.link)
+        || is_ignored_link(ctx, entry)
         || metadata(&entry.target).is_err()
-        || has_no_matching_target(&entry.link)
+        || has_no_matching_target(ctx, entry)?
+        || is_stale_fold(ctx, entry))
+        && delete_prompt(ctx, &entry.link, delete_options)?
     {
-        if delete_prompt(&entry.link, delete_options) {
-            let link = &entry.link;
-
-            if link.is_symlink() {
-                remove_file(link).expect(format!("Couldn't delete {:?}", link).as_str());
-            } else if link.is_dir() {
-                // hope it's empty
-                remove_dir(link).expect(format!("Couldn't delete dir {:?}", link).as_str());
-            } else if link.is_file() {
-                rename(link, name_with_bak(link))
-                    .expect(format!("Couldn't rename file {:?}", link).as_str());
-            } else {
-                eprintln!("WHAT THE");
-                // what's left?
-                return false;
-            }
-            return true;
+        let link = &entry.link;
+
+        if ctx.dry_run {
+            return Ok(true);
         }
+
+        let action = if let Some(trash) = &ctx.trash {
+            Action::Trash {
+                path: link.to_path_buf(),
+                trashed: trash::put(trash, link)?,
+            }
+        } else if link.is_symlink() {
+            let target = resolve_link(link).map_err(on(link))?;
+            remove_file(link).map_err(on(link))?;
+            Action::Unlink {
+                link: link.to_path_buf(),
+                target,
+            }
+        } else if manifest::is_unmodified_copy(ctx, link) {
+            // kept so undo can put this copy back, not whatever the source
+            // has become
+            Action::Uncopy {
+                path: link.to_path_buf(),
+                source: entry.target.to_path_buf(),
+                strategy: entry.strategy,
+                backup: Some(backup::keep_copy(ctx, link)?),
+            }
+        } else if is_empty(link) {
+            remove_dir(link).map_err(on(link))?;
+            Action::RemoveDir {
+                dir: link.to_path_buf(),
+            }
+        } else if link.is_file() || link.is_dir() {
+            Action::Rename {
+                from: link.to_path_buf(),
+                to: backup::back_up(ctx, link)?,
+            }
+        } else {
+            // what's left?
+            return Err(Error::LinkConflict(link.to_path_buf()));
+        };
+        journal::record(ctx, action)?;
+        manifest::forget(ctx, link);
+        return Ok(true);
     }
 
-    false
+    Ok(false)
 }
 
-pub fn delete_prompt(path: &PathBuf, options: &DeleteOptions) -> bool {
-    let mut delete_all = get_delete_all().lock().expect("How did I break mutex");
+pub fn delete_prompt(ctx: &Context, path: &Path, options: &DeleteOptions) -> Result<bool, Error> {
+    if ctx.dry_run {
+        ctx.message()
+            .with_verb(&dry_run_verb(ctx, path, options))
+            .with_path(path)
+            .success(None);
+
+        return Ok(true);
+    }
 
-    let result = if *delete_all || options.without_prompting {
+    let result = if ctx.delete_all.get() || options.without_prompting {
         'y'
+    } else if let Some(answer) = ctx.answers.answer_for(question_for(ctx, path, options)) {
+        answer
     } else {
-        display_delete_prompt(path, options)
+        display_delete_prompt(ctx, path, options)?
     };
 
     if result == 'a' {
-        *delete_all = true;
+        ctx.delete_all.set(true);
     }
 
     if result == 'y' || result == 'a' {
-        Messenger::new()
-            .with_verb(&options.verb_template)
-            .conjugate_with(&"ing")
+        ctx.message()
+            .with_verb(options.verb_template)
+            .conjugate_with("ing")
             .with_path(path)
             .success(None);
 
-        return true;
+        return Ok(true);
     }
 
-    Messenger::new()
-        .with_verb(&"skipping")
-        .with_path(path)
-        .warning(None);
+    log_skipping(ctx, path, result);
 
-    return false;
+    Ok(false)
+}
+
+// replacing a real file means backing it up
+fn question_for<'a>(ctx: &Context, path: &Path, options: &DeleteOptions<'a>) -> &'a str {
+    if options.question == "replace"
+        && !path.is_symlink()
+        && !manifest::is_unmodified_copy(ctx, path)
+    {
+        "backup"
+    } else {
+        options.question
+    }
+}
+
+fn dry_run_verb(ctx: &Context, path: &Path, options: &DeleteOptions) -> String {
+    if ctx.trash.is_some() {
+        String::from("would trash")
+    } else if !path.is_symlink()
+        && (path.is_file() || (path.is_dir() && !is_empty(path)))
+        && !manifest::is_unmodified_copy(ctx, path)
+    {
+        String::from("would back up")
+    } else {
+        format!("would {}", conjugate_with(options.verb_template, "e"))
+    }
 }
@@ -0,0 +1,78 @@
+use std::fs::read;
+use std::path::PathBuf;
+
+use similar::TextDiff;
+
+use crate::{
+    error::on,
+    fs::{absolute_path, DotEntry},
+    plan, template, Context, Error,
+};
+
+// What install would put at the link against the real file there now, or
+// None when they're the same
+pub fn unified(ctx: &Context, entry: &DotEntry) -> Result<Option<String>, Error> {
+    let ours = template::generate(ctx, &entry.target, entry.strategy)?;
+    let theirs = read(&entry.link).map_err(on(&entry.link))?;
+
+    if ours == theirs {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        match (String::from_utf8(theirs), String::from_utf8(ours)) {
+            (Ok(theirs), Ok(ours)) => TextDiff::from_lines(&theirs, &ours)
+                .unified_diff()
+                .header(
+                    &entry.link.display().to_string(),
+                    &entry.target.display().to_string(),
+                )
+                .to_string(),
+            _ => String::from("binary files differ\n"),
+        },
+    ))
+}
+
+pub fn show(ctx: &Context, entry: &DotEntry) -> Result<bool, Error> {
+    match unified(ctx, entry)? {
+        Some(diff) => {
+            ctx.message()
+                .with_verb("diff")
+                .with_path(&entry.link)
+                .with_target(&entry.target)
+                .diff(&diff);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Every real file sitting where install wants to put something, or just
+// the one given
+pub fn run(ctx: &Context, path: Option<&str>) -> Result<(), Error> {
+    let path = path.map(|path| absolute_path(path).unwrap_or_else(|| PathBuf::from(path)));
+    let mut shown = false;
+
+    for planned in &plan::get(ctx)?.entries {
+        let entry = &planned.entry;
+
+        if planned.shadowed_by.is_some()
+            || entry.link.is_symlink()
+            || !entry.link.is_file()
+            || !entry.target.is_file()
+            || path.as_ref().is_some_and(|path| path != &entry.link)
+        {
+            continue;
+        }
+
+        shown |= show(ctx, entry)?;
+    }
+
+    if !shown {
+        ctx.message()
+            .with_verb("diff")
+            .log(Some(String::from("no real files differ from the repo")));
+    }
+
+    Ok(())
+}
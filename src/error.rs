@@ -0,0 +1,123 @@
+use std::env::VarError;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    MissingEnv {
+        name: String,
+        source: VarError,
+    },
+    BadPath {
+        name: String,
+        source: io::Error,
+    },
+    BadConfig {
+        path: PathBuf,
+        message: String,
+    },
+    Template {
+        path: PathBuf,
+        message: String,
+    },
+    NotUtf8(PathBuf),
+    PermissionDenied(PathBuf),
+    LinkConflict(PathBuf),
+    // links that more than one source want with nothing to pick between them
+    Collisions(Vec<(PathBuf, Vec<PathBuf>)>),
+    UserQuit,
+    OutOfSync {
+        out_of_sync: usize,
+        total: usize,
+    },
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::UserQuit => 0,
+            Error::Usage(_)
+            | Error::MissingEnv { .. }
+            | Error::BadPath { .. }
+            | Error::BadConfig { .. }
+            | Error::Collisions(_)
+            | Error::OutOfSync { .. } => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MissingEnv { source, .. } => Some(source),
+            Error::BadPath { source, .. } | Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(cmd) => write!(f, "Usage: {} <command>", cmd),
+            Error::MissingEnv { name, source } => {
+                write!(f, "reading {} environment variable: {}", name, source)
+            }
+            Error::BadPath { name, source } => write!(f, "canonicalizing {}: {}", name, source),
+            Error::BadConfig { path, message } => write!(f, "{}: {}", path.display(), message),
+            Error::Template { path, message } => {
+                write!(f, "rendering {}: {}", path.display(), message)
+            }
+            Error::NotUtf8(path) => write!(f, "path is not UTF-8: {}", path.display()),
+            Error::PermissionDenied(path) => write!(f, "permission denied: {}", path.display()),
+            Error::LinkConflict(path) => {
+                write!(f, "not a link, file or directory: {}", path.display())
+            }
+            Error::Collisions(collisions) => {
+                for (number, (link, sources)) in collisions.iter().enumerate() {
+                    if number > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{} is wanted by", link.display())?;
+                    for source in sources {
+                        write!(f, " {}", source.display())?;
+                    }
+                }
+                Ok(())
+            }
+            Error::UserQuit => write!(f, "quit per user"),
+            Error::OutOfSync { out_of_sync, total } => {
+                write!(f, "{} of {} out of sync", out_of_sync, total)
+            }
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { path: None, source }
+    }
+}
+
+// for map_err, so io errors say which path they were about
+pub fn on(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| match source.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_path_buf()),
+        _ => Error::Io {
+            path: Some(path.to_path_buf()),
+            source,
+        },
+    }
+}
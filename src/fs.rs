@@ -1,28 +1,55 @@
-use crate::{get_home, get_root, get_this, Messenger};
-use std::error::Error;
-use std::fmt;
-use std::fs::{canonicalize, metadata};
+use crate::{
+    context::{Selector, DEFAULT_LABEL},
+    error::on,
+    plan, Context, Error,
+};
+use std::fs::{canonicalize, metadata, read};
+use std::io;
 use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
+#[derive(Debug, Clone)]
 pub struct DotEntry {
     pub link: PathBuf,
     pub target: PathBuf,
+    pub strategy: Strategy,
 }
 
-#[derive(Debug, Clone)]
-struct PathError;
+// How an entry ends up in ~, a symlink unless its name ends in .copy
+// or .tmpl
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Link,
+    Copy,
+    Template,
+}
 
-impl Error for PathError {}
+impl Strategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Strategy::Link => "link",
+            Strategy::Copy => "copy",
+            Strategy::Template => "template",
+        }
+    }
 
-impl fmt::Display for PathError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Trouble parsing path for links")
+    pub fn parse(name: &str) -> Option<Strategy> {
+        match name {
+            "link" => Some(Strategy::Link),
+            "copy" => Some(Strategy::Copy),
+            "template" => Some(Strategy::Template),
+            _ => None,
+        }
     }
 }
 
-pub fn get_dot_path(prefix: Option<&str>) -> PathBuf {
-    let base = get_home();
+pub const FOLD_FILE: &str = ".linkomatic-fold";
+
+const STRATEGY_SUFFIXES: [(&str, Strategy); 2] =
+    [(".copy", Strategy::Copy), (".tmpl", Strategy::Template)];
+
+pub fn get_dot_path(ctx: &Context, prefix: Option<&str>) -> PathBuf {
+    let base = &ctx.home;
 
     match prefix {
         Some(val) => base.join(val),
@@ -30,7 +57,11 @@ pub fn get_dot_path(prefix: Option<&str>) -> PathBuf {
     }
 }
 
-fn link_from_dot_path(dot_path: &PathBuf, prefix_to_strip: Option<&str>) -> Option<PathBuf> {
+fn link_from_dot_path(
+    ctx: &Context,
+    dot_path: &Path,
+    prefix_to_strip: Option<&Path>,
+) -> Option<PathBuf> {
     let base = match prefix_to_strip {
         Some(prefix) => dot_path
             .strip_prefix(prefix)
@@ -38,60 +69,80 @@ fn link_from_dot_path(dot_path: &PathBuf, prefix_to_strip: Option<&str>) -> Opti
         None => dot_path,
     };
 
-    Some(get_dot_path(None).join(PathBuf::from(format!(".{}", base.to_str()?))))
+    Some(get_dot_path(ctx, None).join(PathBuf::from(format!(
+        "{}{}",
+        ctx.config.dot(),
+        base.to_str()?
+    ))))
 }
 
-fn replace_this_labels(entry: PathBuf) -> Option<PathBuf> {
+// Only the file name's own label is rewritten, and only once, so a link
+// side like `_arch` is never read again as the value of another selector
+fn replace_this_labels(ctx: &Context, entry: PathBuf) -> Option<PathBuf> {
     let file_name = file_name_as_str(&entry);
 
-    if file_name.starts_with('_') {
-        let this = get_this();
-
-        let result = PathBuf::from(
-            entry
-                .to_str()?
-                .replace(&(String::from("_") + &this.platform), &"_platform")
-                .replace(&(String::from("_") + &this.machine), &"_machine"),
-        );
+    let renamed = longest_label(&ctx.this.selectors(), file_name, |selector| selector.value)
+        .map(|(selector, rest)| format!("_{}{}", selector.link_side, rest));
 
-        return Some(result);
-    }
+    Some(match renamed {
+        Some(name) => entry.with_file_name(name),
+        None => entry,
+    })
+}
 
-    return Some(entry);
+// The selector whose `_<label>` starts the file name and what follows it.
+// The longest label wins, so `_workstation` isn't taken for a `work` tag,
+// and of equal ones the last and most specific
+pub fn longest_label<'a, 'b>(
+    selectors: &'a [Selector<'a>],
+    file_name: &'b str,
+    label: impl Fn(&Selector<'a>) -> &'a str,
+) -> Option<(&'a Selector<'a>, &'b str)> {
+    selectors
+        .iter()
+        .filter_map(|selector| {
+            file_name
+                .strip_prefix('_')?
+                .strip_prefix(label(selector))
+                .map(|rest| (selector, rest))
+        })
+        .max_by_key(|(selector, _)| label(selector).len())
 }
 
-fn final_link_name(path: &PathBuf, prefix_to_strip: Option<&str>) -> Option<PathBuf> {
-    replace_this_labels(link_from_dot_path(path, prefix_to_strip)?)
+fn final_link_name(ctx: &Context, path: &Path, prefix_to_strip: Option<&Path>) -> Option<PathBuf> {
+    replace_this_labels(ctx, link_from_dot_path(ctx, path, prefix_to_strip)?)
 }
 
-fn final_target_name(path: &PathBuf) -> Option<PathBuf> {
-    let root = get_root();
+fn final_target_name(ctx: &Context, path: &Path) -> Option<PathBuf> {
+    let root = &ctx.root;
 
     if !(path.is_symlink()) {
         return Some(root.join(path));
     }
 
     let sub_target = path.read_link().expect("Why can I not read this link?");
-
-    if sub_target.is_absolute() {
-        Messenger::new()
-            .with_verb("skipping")
-            .with_path(&root.join(path))
-            .warning(Some(format!(
-                "Absolute paths not supported, links to: {}",
-                &sub_target.display()
-            )));
-
-        return None;
-    }
+    let absolute = sub_target.is_absolute();
 
     match canonicalize(
         root.join(path.parent().expect("Why is there no parent?"))
-            .join(sub_target),
+            .join(&sub_target),
     ) {
+        Ok(target)
+            if absolute && !target.starts_with(root) && !ctx.config.allows_outside(&target) =>
+        {
+            ctx.message()
+                .with_verb("skipping")
+                .with_path(&root.join(path))
+                .warning(Some(format!(
+                    "links outside LINKOMATIC_ROOT to {}, add it to allow_outside in link_o_matic.toml",
+                    &sub_target.display()
+                )));
+
+            None
+        }
         Ok(target) => Some(target),
         Err(err) => {
-            Messenger::new()
+            ctx.message()
                 .with_verb("skipping")
                 .with_path(&root.join(path))
                 .warning(Some(format!(
@@ -104,42 +155,80 @@ fn final_target_name(path: &PathBuf) -> Option<PathBuf> {
     }
 }
 
-pub fn has_bad_underscore(path: &PathBuf) -> bool {
+pub fn has_bad_underscore(ctx: &Context, path: &Path) -> bool {
     let file_name = file_name_as_str(path);
 
     if file_name.starts_with('_') {
-        !(file_name.starts_with(&"_machine") || file_name.starts_with(&"_platform"))
+        !ctx.this
+            .selectors()
+            .iter()
+            .any(|selector| file_name.starts_with(&format!("_{}", selector.link_side)))
     } else {
         false
     }
 }
 
-pub fn has_no_matching_target(path: &PathBuf) -> bool {
-    let [name, home, root] =
-        [path, get_home(), get_root()].map(|buf| buf.to_str().expect("why no strings?"));
+// the link is dangling, or install wouldn't make it like this any more,
+// because its source is gone or another variant now wins its name
+pub fn has_no_matching_target(ctx: &Context, entry: &DotEntry) -> Result<bool, Error> {
+    let current = match metadata(&entry.link) {
+        Ok(current) => current,
+        Err(_) => return Ok(true),
+    };
 
-    let base_path = name.replace(&(String::from("") + home + "."), root);
+    Ok(match plan::get(ctx)?.chosen(&entry.link) {
+        // copies are compared by source, the inode is always different
+        Some(chosen) if entry.strategy != Strategy::Link => chosen.target != entry.target,
+        Some(chosen) => !metadata(&chosen.target).is_ok_and(|t| is_identical(&t, &current)),
+        None => true,
+    })
+}
+
+// a directory that is linked as a whole instead of file by file
+pub fn is_folded(ctx: &Context, dir: &Path) -> bool {
+    dir.join(FOLD_FILE).is_file()
+        || dir
+            .strip_prefix(source_dir(ctx))
+            .is_ok_and(|relative| ctx.config.is_folded(relative))
+}
 
-    metadata(base_path).is_err()
+// a link to a directory in the source that isn't folded any more
+pub fn is_stale_fold(ctx: &Context, entry: &DotEntry) -> bool {
+    entry.target.starts_with(source_dir(ctx))
+        && entry.target.is_dir()
+        && !is_folded(ctx, &entry.target)
 }
 
 pub fn walk_dir(
-    dir: &PathBuf,
-    doit: &dyn Fn(PathBuf) -> Result<(), Box<dyn Error>>,
-) -> Result<(), Box<dyn Error>> {
+    ctx: &Context,
+    dir: &Path,
+    doit: &dyn Fn(PathBuf) -> Result<(), Error>,
+) -> Result<(), Error> {
+    walk_dir_with(ctx, dir, &|_| true, doit)
+}
+
+// descend decides whether a real directory is walked into or handed to
+// doit like a file
+fn walk_dir_with(
+    ctx: &Context,
+    dir: &Path,
+    descend: &dyn Fn(&Path) -> bool,
+    doit: &dyn Fn(PathBuf) -> Result<(), Error>,
+) -> Result<(), Error> {
     if dir.is_dir() {
         dir.read_dir().map_or_else(
             |e| {
-                Ok(Messenger::new()
+                ctx.message()
                     .with_verb("skipping")
-                    .with_path(&dir)
-                    .warning(Some(format!("couldn't read: {}", e))))
+                    .with_path(dir)
+                    .warning(Some(format!("couldn't read: {}", e)));
+                Ok(())
             },
-            |entries| -> Result<(), Box<dyn Error>> {
+            |entries| -> Result<(), Error> {
                 for entry in entries {
-                    let path = entry?.path();
-                    if !path.is_symlink() && path.is_dir() {
-                        walk_dir(&path, doit)?
+                    let path = entry.map_err(on(dir))?.path();
+                    if !path.is_symlink() && path.is_dir() && descend(&path) {
+                        walk_dir_with(ctx, &path, descend, doit)?
                     } else {
                         doit(path)?
                     }
@@ -152,101 +241,250 @@ pub fn walk_dir(
     }
 }
 
+// LINKOMATIC_ROOT/home unless the config says otherwise
+pub fn source_dir(ctx: &Context) -> PathBuf {
+    ctx.root.join(&ctx.config.source)
+}
+
+// doit gets the path in the source directory along with its entry
 pub fn find_targets_for_linking(
-    dir_name: &str,
-    doit: &dyn Fn(DotEntry) -> Result<(), Box<dyn Error>>,
-) -> Result<(), Box<dyn Error>> {
-    walk_dir(&PathBuf::from(dir_name), &|path: PathBuf| -> Result<
-        (),
-        Box<dyn Error>,
-    > {
-        match (
-            final_link_name(&path, Some(dir_name)),
-            final_target_name(&path),
-        ) {
-            (Some(link), Some(target)) => doit(DotEntry { link, target }),
-            (Some(_), None) => Ok(()),
-            _ => Err(PathError.into()),
+    ctx: &Context,
+    doit: &dyn Fn(&Path, DotEntry) -> Result<(), Error>,
+) -> Result<(), Error> {
+    walk_source(ctx, &source_dir(ctx), doit)
+}
+
+// like walk_dir, but folded directories are entries themselves and
+// ignored directories aren't entered at all
+fn walk_source(
+    ctx: &Context,
+    dir: &Path,
+    doit: &dyn Fn(&Path, DotEntry) -> Result<(), Error>,
+) -> Result<(), Error> {
+    walk_dir_with(
+        ctx,
+        dir,
+        &|path: &Path| !is_ignored(ctx, path) && !is_folded(ctx, path),
+        &|path: PathBuf| -> Result<(), Error> {
+            if is_ignored(ctx, &path) {
+                return Ok(());
+            }
+
+            match dot_entry(ctx, &path)? {
+                Some(entry) => doit(&path, entry),
+                None => Ok(()),
+            }
+        },
+    )
+}
+
+pub fn dot_entry(ctx: &Context, path: &Path) -> Result<Option<DotEntry>, Error> {
+    let source = source_dir(ctx);
+
+    match (
+        final_link_name(ctx, path, Some(&source)),
+        final_target_name(ctx, path),
+    ) {
+        (Some(link), Some(target)) => {
+            let (link, strategy) = strip_strategy_suffix(link);
+            let strategy = path
+                .strip_prefix(&source)
+                .ok()
+                .and_then(|relative| ctx.config.strategy_for(relative))
+                .unwrap_or(strategy);
+
+            Ok(Some(DotEntry {
+                link,
+                target,
+                strategy,
+            }))
         }
-    })
+        (Some(_), None) => Ok(None),
+        _ => Err(Error::NotUtf8(path.to_path_buf())),
+    }
+}
+
+fn strip_strategy_suffix(link: PathBuf) -> (PathBuf, Strategy) {
+    let file_name = file_name_as_str(&link);
+
+    for (suffix, strategy) in STRATEGY_SUFFIXES {
+        match file_name.strip_suffix(suffix) {
+            Some(name) if !name.is_empty() => return (link.with_file_name(name), strategy),
+            _ => continue,
+        }
+    }
+
+    (link, Strategy::Link)
+}
+
+// by a .linkomaticignore or the config's ignore patterns
+pub fn is_ignored(ctx: &Context, path: &Path) -> bool {
+    ctx.ignores.is_ignored(path)
+        || path
+            .strip_prefix(source_dir(ctx))
+            .is_ok_and(|relative| ctx.config.is_ignored(relative))
+}
+
+// A link whose source, or the file in the source directory it would have
+// come from, is now ignored
+pub fn is_ignored_link(ctx: &Context, entry: &DotEntry) -> bool {
+    let source = source_dir(ctx);
+
+    (entry.target.starts_with(&source) && is_ignored(ctx, &entry.target))
+        || source_for_link(ctx, &entry.link).is_some_and(|path| is_ignored(ctx, &path))
+}
+
+// the reverse of final_link_name, without any .copy or .tmpl suffix
+fn source_for_link(ctx: &Context, link: &Path) -> Option<PathBuf> {
+    let relative = link
+        .strip_prefix(&ctx.home)
+        .ok()?
+        .to_str()?
+        .strip_prefix(ctx.config.dot())?;
+    let relative = Path::new(relative);
+    let file_name = file_name_as_str(relative);
+
+    // a _machine link is the machine's own file before the default
+    let selectors: Vec<Selector> = ctx
+        .this
+        .selectors()
+        .into_iter()
+        .filter(|selector| selector.value != DEFAULT_LABEL)
+        .collect();
+    let file_name = longest_label(&selectors, file_name, |selector| selector.link_side)
+        .map(|(selector, rest)| format!("_{}{}", selector.value, rest))
+        .unwrap_or_else(|| file_name.to_string());
+
+    Some(source_dir(ctx).join(relative.with_file_name(file_name)))
 }
 
 pub fn find_links_to_targets(
-    process: &dyn Fn(PathBuf) -> Result<(), Box<dyn Error>>,
-) -> Result<(), Box<dyn Error>> {
-    let root = get_root();
+    ctx: &Context,
+    process: &dyn Fn(PathBuf) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let home = get_dot_path(ctx, None);
+    let source = source_dir(ctx);
 
     // Check at root of ~
-    for entry in get_dot_path(None).read_dir()? {
-        let path = entry?.path();
+    for entry in home.read_dir().map_err(on(&home))? {
+        let path = entry.map_err(on(&home))?.path();
         process(path)?;
     }
 
     // for every directory in root of .dotfiles/home/
-    // check correspondingly name directory in ~/
-    for entry in root.join("home").read_dir()? {
-        let path = entry?.path();
+    // check correspondingly name directory in ~/, unless that is a link
+    // itself, walking it would be walking the repo
+    for entry in source.read_dir().map_err(on(&source))? {
+        let path = entry.map_err(on(&source))?.path();
         if path.is_dir() {
             let dirname = path
                 .file_name()
                 .and_then(|name| name.to_str())
-                .map(|name| format!(".{}", name));
-            walk_dir(&get_dot_path(dirname.as_deref()), &process)?;
+                .map(|name| format!("{}{}", ctx.config.dot(), name));
+            let dir = get_dot_path(ctx, dirname.as_deref());
+            if !dir.is_symlink() {
+                walk_dir(ctx, &dir, &process)?;
+            }
         }
     }
     Ok(())
 }
 
-pub fn home_path_starts_with_dot(path: &PathBuf) -> bool {
-    let relative_path = &path.strip_prefix(get_dot_path(None));
-    relative_path.as_ref().map_or(false, |rp| {
-        rp.to_str().map_or(false, |p| p.starts_with("."))
+// a path given on the command line, with its directory canonicalized but
+// not the file itself, it's likely a link
+pub fn absolute_path(path: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(path);
+    let parent = canonicalize(match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
     })
+    .ok()?;
+
+    Some(parent.join(path.file_name()?))
+}
+
+pub fn home_path_starts_with_dot(ctx: &Context, path: &Path) -> bool {
+    let relative_path = &path.strip_prefix(&ctx.home);
+    relative_path
+        .as_ref()
+        .is_ok_and(|rp| rp.to_str().is_some_and(|p| p.starts_with(ctx.config.dot())))
+}
+
+// a symlink's target as an absolute path, a relative one taken from where
+// the link lives without following anything on the way
+pub fn resolve_link(link: &Path) -> io::Result<PathBuf> {
+    let target = link.read_link()?;
+    let parent = link.parent().unwrap_or(Path::new("/"));
+
+    let mut resolved = PathBuf::new();
+    for component in parent.join(target).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+
+    Ok(resolved)
+}
+
+// the way from the directory a link lives in to its target
+pub fn relative_to_link(link: &Path, target: &Path) -> PathBuf {
+    let from: Vec<Component> = link
+        .parent()
+        .map(|parent| parent.components().collect())
+        .unwrap_or_default();
+    let to: Vec<Component> = target.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut relative: PathBuf = (common..from.len()).map(|_| Component::ParentDir).collect();
+    relative.extend(&to[common..]);
+    relative
 }
 
 pub fn is_identical(a: &dyn MetadataExt, b: &dyn MetadataExt) -> bool {
     [a.dev(), a.ino()] == [b.dev(), b.ino()]
 }
 
-fn file_name_as_str(word: &PathBuf) -> &str {
+fn file_name_as_str(word: &Path) -> &str {
     word.file_name()
         .and_then(|w| w.to_str())
-        .expect("Why is there no file name")
+        .unwrap_or_default()
 }
 
-pub fn is_invalid_to_target(entry: &PathBuf) -> bool {
+pub fn is_invalid_to_target(ctx: &Context, entry: &Path) -> bool {
     let file_name = file_name_as_str(entry);
 
     if file_name.starts_with('_') {
-        let this = get_this();
-
-        if file_name.starts_with(&format!("_{}", &this.platform))
-            || file_name.starts_with(&format!("_{}", &this.machine))
-        {
-            return false;
-        }
-
-        return true;
+        return !ctx
+            .this
+            .selectors()
+            .iter()
+            .any(|selector| file_name.starts_with(&format!("_{}", selector.value)));
     }
 
-    return false;
+    false
 }
 
-pub fn is_empty(path: &PathBuf) -> bool {
+pub fn is_empty(path: &Path) -> bool {
     path.is_dir()
         && path
             .read_dir()
-            .and_then(|p| Ok(p.count() == 0))
-            .expect("seems you tried to read a dir you cannot read")
-}
-
-pub fn name_with_bak(path: &PathBuf) -> PathBuf {
-    path.with_extension(match path.extension() {
-        Some(e) => format!(
-            "{}.bak",
-            e.to_str()
-                .expect("Why can't this extension be cast as a str?")
-        ),
-        None => String::from("bak"),
-    })
+            .map(|mut p| p.next().is_none())
+            .unwrap_or(false)
+}
+
+// FNV-1a, only used to tell whether a copy has changed
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    Ok(hash_bytes(&read(path)?))
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{:016x}", hash)
 }
@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::Error;
+
+pub const IGNORE_FILE: &str = ".linkomaticignore";
+
+// Every .linkomaticignore in LINKOMATIC_ROOT and the source tree, each
+// one's patterns relative to where it lives, deepest first so it wins
+#[derive(Debug, Default)]
+pub struct Ignores {
+    rules: Vec<Gitignore>,
+}
+
+impl Ignores {
+    pub fn load(root: &Path, source: &Path) -> Result<Ignores, Error> {
+        let mut files = vec![root.join(IGNORE_FILE)];
+        find_ignore_files(source, &mut files);
+        files.dedup();
+
+        let mut rules = vec![];
+        for file in files.into_iter().filter(|f| f.is_file()) {
+            let dir = file.parent().expect("ignore files live in a directory");
+            let mut builder = GitignoreBuilder::new(dir);
+
+            if let Some(e) = builder.add(&file) {
+                return Err(Error::BadConfig {
+                    path: file,
+                    message: e.to_string(),
+                });
+            }
+
+            rules.push(builder.build().map_err(|e| Error::BadConfig {
+                path: file.clone(),
+                message: e.to_string(),
+            })?);
+        }
+
+        rules.sort_by_key(|rules| std::cmp::Reverse(rules.path().components().count()));
+
+        Ok(Ignores { rules })
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+            return true;
+        }
+
+        let is_dir = !path.is_symlink() && path.is_dir();
+
+        for rules in self.rules.iter().filter(|r| path.starts_with(r.path())) {
+            let matched = rules.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            } else if matched.is_whitelist() {
+                return false;
+            }
+        }
+
+        false
+    }
+}
+
+fn find_ignore_files(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+        if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+            found.push(path);
+        } else if !path.is_symlink() && path.is_dir() {
+            find_ignore_files(&path, found);
+        }
+    }
+}
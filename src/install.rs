@@ -1,120 +1,559 @@
-use std::env;
-use std::error::Error;
-use std::fs::{create_dir_all, metadata, read_link, symlink_metadata};
+use std::fs::{
+    create_dir_all, metadata, remove_dir, remove_file, rename, set_permissions, symlink_metadata,
+    write,
+};
 use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
 
 use crate::{
+    adopt::merge_dir,
+    backup,
     delete::{decide_delete, DeleteOptions},
-    fs::{find_targets_for_linking, is_identical, is_invalid_to_target, DotEntry},
-    get_root,
-    messages::Messenger,
+    error::on,
+    fs::{
+        content_hash, dot_entry, hash_bytes, is_identical, is_invalid_to_target, relative_to_link,
+        resolve_link, DotEntry, Strategy,
+    },
+    journal::{self, Action},
+    manifest,
+    messages::{display_delete_prompt, log_skipping},
+    plan, template, Context, Error,
 };
 
-pub fn run() -> Result<(), Box<dyn Error>> {
-    env::set_current_dir(get_root())?;
+const REPLACE_OPTIONS: DeleteOptions = DeleteOptions {
+    implode: true,
+    without_prompting: false,
+    verb_template: "replac%",
+    question: "replace",
+    can_merge: false,
+    can_pull: false,
+    diff_with: None,
+};
 
-    let replace_options = &DeleteOptions {
-        implode: true,
-        without_prompting: false,
-        verb_template: &"replac%",
-    };
+const AUTO_REPLACE_OPTIONS: DeleteOptions = DeleteOptions {
+    implode: true,
+    without_prompting: true,
+    verb_template: "autoreplac%",
+    question: "replace",
+    can_merge: false,
+    can_pull: false,
+    diff_with: None,
+};
 
-    let auto_replace_options = &DeleteOptions {
-        implode: true,
-        without_prompting: true,
-        verb_template: &"autoreplac%",
-    };
+// asked before anything happens to a real file or directory in the way
+const REAL_OPTIONS: DeleteOptions = DeleteOptions {
+    implode: true,
+    without_prompting: false,
+    verb_template: "replac%",
+    question: "backup",
+    can_merge: false,
+    can_pull: false,
+    diff_with: None,
+};
+
+const BACK_UP_OPTIONS: DeleteOptions = DeleteOptions {
+    without_prompting: true,
+    ..REAL_OPTIONS
+};
 
-    let process_link = |entry: DotEntry| -> Result<(), Box<dyn Error>> {
-        if decide_link(&entry, replace_options, auto_replace_options) {
-            create_link(entry)?
+pub fn run(ctx: &Context) -> Result<(), Error> {
+    for planned in &plan::get(ctx)?.entries {
+        match &planned.shadowed_by {
+            Some(winner) => ctx
+                .message()
+                .with_verb("ignoring")
+                .with_path(&planned.entry.link)
+                .with_target_shown(&planned.entry.target)
+                .log(Some(format!("shadowed by {}", winner.display()))),
+            None => install_entry(ctx, planned.entry.clone())?,
         }
-        Ok(())
-    };
+    }
 
-    find_targets_for_linking(&"home", &process_link)?;
+    manifest::save(ctx)
+}
 
+pub fn install_entry(ctx: &Context, entry: DotEntry) -> Result<(), Error> {
+    if decide_link(ctx, &entry)? {
+        create_link(ctx, entry)?
+    }
     Ok(())
 }
 
-fn create_link(entry: DotEntry) -> Result<(), Box<dyn Error>> {
-    create_dir_all(entry.link.parent().expect("What is this at the root?"))?;
-    symlink(entry.target, entry.link)?;
-    Ok(())
+fn create_link(ctx: &Context, entry: DotEntry) -> Result<(), Error> {
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    let parent = entry.link.parent().expect("What is this at the root?");
+    create_dir_all(parent).map_err(on(parent))?;
+
+    match entry.strategy {
+        Strategy::Link => {
+            make_symlink(ctx, &entry.target, &entry.link)?;
+            manifest::record(ctx, &entry.link, &entry.target);
+            journal::record(
+                ctx,
+                Action::Link {
+                    link: entry.link,
+                    target: entry.target,
+                },
+            )
+        }
+        Strategy::Copy | Strategy::Template => {
+            let hash = write_copy(ctx, &entry.link, &entry.target, entry.strategy)?;
+            journal::record(
+                ctx,
+                Action::Copy {
+                    path: entry.link,
+                    source: entry.target,
+                    hash,
+                },
+            )
+        }
+    }
 }
 
-fn decide_link(
-    entry: &DotEntry,
-    replace_options: &DeleteOptions,
-    auto_replace_options: &DeleteOptions,
-) -> bool {
-    if is_invalid_to_target(&entry.target) {
-        Messenger::new()
-            .with_path(&entry.link)
-            .with_verb("ignoring")
-            .log(None);
-        return false;
+// Absolute unless the config asks for links relative to where they live
+pub fn make_symlink(ctx: &Context, target: &Path, link: &Path) -> Result<(), Error> {
+    if ctx.config.relative_links {
+        symlink(relative_to_link(link, target), link)
+    } else {
+        symlink(target, link)
+    }
+    .map_err(on(link))
+}
+
+// Writes a copy, or the rendered template, with the permissions of its
+// source and returns the hash of what was written
+pub fn write_copy(
+    ctx: &Context,
+    path: &Path,
+    source: &Path,
+    strategy: Strategy,
+) -> Result<String, Error> {
+    let contents = template::generate(ctx, source, strategy)?;
+    let permissions = metadata(source).map_err(on(source))?.permissions();
+
+    let parent = path.parent().expect("What is this at the root?");
+    create_dir_all(parent).map_err(on(parent))?;
+    write(path, &contents).map_err(on(path))?;
+    set_permissions(path, permissions).map_err(on(path))?;
+
+    let hash = hash_bytes(&contents);
+    manifest::record_copy(ctx, path, source, strategy, hash.clone());
+    Ok(hash)
+}
+
+// what a folded directory leaves in ~ when it was linked file by file
+fn holds_only_our_links(ctx: &Context, dir: &Path) -> bool {
+    dir.read_dir().is_ok_and(|entries| {
+        entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .all(|path| {
+                if path.is_symlink() {
+                    resolve_link(&path).is_ok_and(|target| {
+                        target.starts_with(&ctx.root)
+                            || manifest::is_recorded_link(ctx, &path, &target)
+                    })
+                } else {
+                    path.is_dir() && holds_only_our_links(ctx, &path)
+                }
+            })
+    })
+}
+
+fn unlink_dir(ctx: &Context, dir: &Path) -> Result<(), Error> {
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    for entry in dir.read_dir().map_err(on(dir))? {
+        let path = entry.map_err(on(dir))?.path();
+
+        if path.is_symlink() {
+            let target = resolve_link(&path).map_err(on(&path))?;
+            remove_file(&path).map_err(on(&path))?;
+            manifest::forget(ctx, &path);
+            journal::record(ctx, Action::Unlink { link: path, target })?;
+        } else {
+            unlink_dir(ctx, &path)?;
+        }
+    }
+
+    remove_dir(dir).map_err(on(dir))?;
+    journal::record(
+        ctx,
+        Action::RemoveDir {
+            dir: dir.to_path_buf(),
+        },
+    )
+}
+
+pub enum LinkState {
+    Ignored,
+    Broken(String),
+    Missing,
+    Linked,
+    Copied,
+    Stale,
+    Modified,
+    Elsewhere { old_target: PathBuf, dead: bool },
+    Blocked,
+}
+
+pub fn link_state(ctx: &Context, entry: &DotEntry) -> LinkState {
+    if is_invalid_to_target(ctx, &entry.target) {
+        return LinkState::Ignored;
     }
 
     let new_target_stat = match metadata(&entry.target) {
         Ok(s) => s,
-        Err(e) => {
-            Messenger::new()
-                .with_verb("skipping")
-                .with_path(&entry.link)
-                .warning(Some(format!("Broken link: {}", e)));
-            return false;
-        }
+        Err(e) => return LinkState::Broken(e.to_string()),
+    };
+
+    // a template that can't be rendered is as broken as a missing source
+    let contents_hash = match entry.strategy {
+        Strategy::Link => None,
+        _ => match template::generate(ctx, &entry.target, entry.strategy) {
+            Ok(contents) => Some(hash_bytes(&contents)),
+            Err(e) => return LinkState::Broken(e.to_string()),
+        },
     };
 
     let link_stat = match symlink_metadata(&entry.link) {
         Ok(s) => s,
-        Err(_) => {
-            Messenger::new()
-                .with_verb("linking")
-                .with_path(&entry.link)
-                .success(None);
-            return true;
-        }
+        Err(_) => return LinkState::Missing,
     };
 
-    let current_target_exists = match metadata(&entry.link) {
-        Ok(current_target) if is_identical(&new_target_stat, &current_target) => {
-            Messenger::new().with_path(&entry.link).log(None);
-            return false;
+    if let Some(contents_hash) = contents_hash.filter(|_| !link_stat.is_symlink()) {
+        return copy_state(ctx, entry, &contents_hash);
+    }
+
+    let current_target = metadata(&entry.link);
+
+    if let Ok(current_target) = &current_target {
+        if entry.strategy == Strategy::Link && is_identical(&new_target_stat, current_target) {
+            return LinkState::Linked;
         }
-        Ok(_) => true,
-        Err(_) => false,
-    };
+    }
 
-    let old_target_link = link_stat
+    match link_stat
         .is_symlink()
-        .then(|| read_link(&entry.link))
-        .and_then(|p| p.ok());
-    let old_target = old_target_link.as_ref().and_then(|p| p.to_str());
+        .then(|| resolve_link(&entry.link))
+        .and_then(|p| p.ok())
+    {
+        Some(old_target) => LinkState::Elsewhere {
+            old_target,
+            dead: current_target.is_err(),
+        },
+        None => LinkState::Blocked,
+    }
+}
 
-    Messenger::new()
+// copies are compared by contents, the inode is always different, and a
+// file we never wrote is only in the way
+fn copy_state(ctx: &Context, entry: &DotEntry, contents_hash: &str) -> LinkState {
+    if !entry.link.is_file() {
+        return LinkState::Blocked;
+    }
+
+    match content_hash(&entry.link) {
+        Ok(copied) if copied == contents_hash => LinkState::Copied,
+        _ if manifest::is_unmodified_copy(ctx, &entry.link) => LinkState::Stale,
+        _ if manifest::is_recorded_copy(ctx, &entry.link) => LinkState::Modified,
+        _ => LinkState::Blocked,
+    }
+}
+
+// A real directory where the link goes can be backed up, merged into the
+// repo when the link is to a directory there too, or left alone
+fn decide_directory(ctx: &Context, entry: &DotEntry) -> Result<bool, Error> {
+    let can_merge = entry.target.is_dir();
+
+    ctx.message()
         .with_verb("found")
         .with_path(&entry.link)
-        .warning(Some(match old_target {
-            Some(link) => format!(
-                "Link already exists and points elsewhere: {} {}",
-                link,
-                if current_target_exists {
-                    &""
+        .warning(Some(String::from(if can_merge {
+            "A real directory is in the way, it can be backed up or merged into the repo"
+        } else {
+            "A real directory is in the way, it will be backed up"
+        })));
+
+    let answer = ask_about_real(
+        ctx,
+        &entry.link,
+        &DeleteOptions {
+            can_merge,
+            ..REAL_OPTIONS
+        },
+    )?;
+
+    if answer == 'm' {
+        merge_dir(ctx, &entry.link, &entry.target)?;
+
+        // whatever the repo already had is backed up instead
+        return Ok(
+            entry.link.symlink_metadata().is_err() || decide_delete(ctx, entry, &BACK_UP_OPTIONS)?
+        );
+    }
+
+    back_up_or_skip(ctx, entry, answer)
+}
+
+// A real file where the link goes is backed up, or pulled into the repo so
+// what was changed on this machine is kept
+fn decide_real_file(ctx: &Context, entry: &DotEntry, found: &str) -> Result<bool, Error> {
+    let pull_to = pull_destination(ctx, entry)?;
+
+    ctx.message()
+        .with_verb("found")
+        .with_path(&entry.link)
+        .warning(Some(format!(
+            "{}, {}",
+            found,
+            if pull_to.is_some() {
+                "it can be backed up or pulled into the repo"
+            } else {
+                "it will be backed up"
+            }
+        )));
+
+    let Some(dest) = pull_to else {
+        return decide_delete(ctx, entry, &REPLACE_OPTIONS);
+    };
+
+    let answer = ask_about_real(
+        ctx,
+        &entry.link,
+        &DeleteOptions {
+            can_pull: true,
+            diff_with: Some(entry),
+            ..REAL_OPTIONS
+        },
+    )?;
+
+    if answer == 'p' {
+        return pull(ctx, entry, &dest);
+    }
+
+    back_up_or_skip(ctx, entry, answer)
+}
+
+// The answer file can ask for merging or pulling ahead of the catch-all
+// answers, a dry run only ever shows the backup
+fn ask_about_real(ctx: &Context, path: &Path, options: &DeleteOptions) -> Result<char, Error> {
+    let instead = if options.can_merge {
+        Some(('m', "merge"))
+    } else if options.can_pull {
+        Some(('p', "pull"))
+    } else {
+        None
+    };
+
+    Ok(if ctx.dry_run {
+        'y'
+    } else if let Some((choice, _)) =
+        instead.filter(|(_, question)| ctx.answers.rule_for(question) == Some('y'))
+    {
+        choice
+    } else if ctx.delete_all.get() {
+        'y'
+    } else if let Some(answer) = ctx.answers.answer_for(options.question) {
+        answer
+    } else {
+        display_delete_prompt(ctx, path, options)?
+    })
+}
+
+fn back_up_or_skip(ctx: &Context, entry: &DotEntry, answer: char) -> Result<bool, Error> {
+    match answer {
+        'y' | 'a' => {
+            if answer == 'a' {
+                ctx.delete_all.set(true);
+            }
+            decide_delete(ctx, entry, &BACK_UP_OPTIONS)
+        }
+        _ => {
+            log_skipping(ctx, &entry.link, answer);
+            Ok(false)
+        }
+    }
+}
+
+// Over the file it would be linked to, or beside it as this machine's own
+// when the link is a `_machine` one filled in by a shared file. Templates
+// only take a variant, their rendered output can't replace them
+fn pull_destination(ctx: &Context, entry: &DotEntry) -> Result<Option<PathBuf>, Error> {
+    if !entry.target.is_file() {
+        return Ok(None);
+    }
+
+    let plan = plan::get(ctx)?;
+    let machine = format!("_{}", ctx.this.machine);
+    let rest = entry
+        .link
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("_machine"));
+
+    match (rest, plan.source_for(&entry.link)) {
+        (Some(rest), Some(source))
+            if !source
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&machine)) =>
+        {
+            let variant = source.with_file_name(format!(
+                "{}{}{}",
+                machine,
+                rest,
+                if entry.strategy == Strategy::Link {
+                    ""
                 } else {
-                    &"(dead: auto-replacing)"
+                    ".copy"
                 }
+            ));
+
+            Ok(variant.symlink_metadata().is_err().then_some(variant))
+        }
+        _ if entry.strategy == Strategy::Template => Ok(None),
+        _ => Ok(Some(entry.target.clone())),
+    }
+}
+
+// Moves the real file into the repo, backing up whatever was there, and
+// links to it, from the new source file if it made one
+fn pull(ctx: &Context, entry: &DotEntry, dest: &Path) -> Result<bool, Error> {
+    ctx.message()
+        .with_verb("pulling")
+        .with_path(&entry.link)
+        .with_target_shown(dest)
+        .success(None);
+
+    if dest.symlink_metadata().is_ok() {
+        journal::record(
+            ctx,
+            Action::Rename {
+                from: dest.to_path_buf(),
+                to: backup::back_up(ctx, dest)?,
+            },
+        )?;
+    }
+
+    rename(&entry.link, dest).map_err(on(&entry.link))?;
+    journal::record(
+        ctx,
+        Action::Rename {
+            from: entry.link.clone(),
+            to: dest.to_path_buf(),
+        },
+    )?;
+    manifest::forget(ctx, &entry.link);
+
+    if dest == entry.target {
+        install_entry(ctx, entry.clone())?;
+    } else {
+        plan::forget(ctx);
+        if let Some(entry) = dot_entry(ctx, dest)? {
+            install_entry(ctx, entry)?;
+        }
+    }
+    Ok(false)
+}
+
+fn decide_link(ctx: &Context, entry: &DotEntry) -> Result<bool, Error> {
+    let (message, dead) = match link_state(ctx, entry) {
+        LinkState::Ignored => {
+            ctx.message()
+                .with_path(&entry.link)
+                .with_verb("ignoring")
+                .log(None);
+            return Ok(false);
+        }
+        LinkState::Broken(e) => {
+            ctx.message()
+                .with_verb("skipping")
+                .with_path(&entry.link)
+                .warning(Some(format!("Broken link: {}", e)));
+            return Ok(false);
+        }
+        LinkState::Missing => {
+            ctx.message()
+                .with_verb(match (entry.strategy, ctx.dry_run) {
+                    (Strategy::Link, true) => "would link",
+                    (Strategy::Link, false) => "linking",
+                    (Strategy::Copy, true) => "would copy",
+                    (Strategy::Copy, false) => "copying",
+                    (Strategy::Template, true) => "would generate",
+                    (Strategy::Template, false) => "generating",
+                })
+                .with_path(&entry.link)
+                .with_target(&entry.target)
+                .success(None);
+            return Ok(true);
+        }
+        LinkState::Linked => {
+            manifest::record(ctx, &entry.link, &entry.target);
+            ctx.message()
+                .with_path(&entry.link)
+                .with_target(&entry.target)
+                .log(None);
+            return Ok(false);
+        }
+        LinkState::Copied => {
+            if let Ok(hash) = content_hash(&entry.link) {
+                manifest::record_copy(ctx, &entry.link, &entry.target, entry.strategy, hash);
+            }
+            ctx.message()
+                .with_path(&entry.link)
+                .with_target(&entry.target)
+                .log(None);
+            return Ok(false);
+        }
+        LinkState::Stale => (
+            "Copy is out of date and unchanged since copied (auto-replacing)".to_string(),
+            true,
+        ),
+        LinkState::Modified => {
+            return decide_real_file(ctx, entry, "Copy differs from the repo");
+        }
+        LinkState::Elsewhere { old_target, dead } => (
+            format!(
+                "Link already exists and points elsewhere: {} {}",
+                old_target.display(),
+                if dead { "(dead: auto-replacing)" } else { "" }
             ),
-            None => "File exists and is not a link, a .bak will be made".to_string(),
-        }));
+            dead,
+        ),
+        LinkState::Blocked if entry.link.is_dir() => {
+            if holds_only_our_links(ctx, &entry.link) {
+                ctx.message()
+                    .with_verb(if ctx.dry_run { "would fold" } else { "folding" })
+                    .with_path(&entry.link)
+                    .with_target(&entry.target)
+                    .success(Some(String::from("replacing a directory of links")));
+                return unlink_dir(ctx, &entry.link).map(|_| true);
+            }
+
+            return decide_directory(ctx, entry);
+        }
+        LinkState::Blocked => {
+            return decide_real_file(ctx, entry, "File exists and is not a link");
+        }
+    };
+
+    ctx.message()
+        .with_verb("found")
+        .with_path(&entry.link)
+        .warning(Some(message));
 
     decide_delete(
-        &entry,
-        if current_target_exists {
-            replace_options
+        ctx,
+        entry,
+        if dead {
+            &AUTO_REPLACE_OPTIONS
         } else {
-            auto_replace_options
+            &REPLACE_OPTIONS
         },
     )
 }
@@ -0,0 +1,358 @@
+use std::cell::RefCell;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    error::on,
+    fs::{content_hash, resolve_link, Strategy},
+    install::{make_symlink, write_copy},
+    manifest,
+    messages::conjugate_with,
+    trash, Context, Error,
+};
+
+const JOURNAL_DIR: &str = "journal";
+
+// One line per change made to the file system, so a run can be reversed
+pub enum Action {
+    Link {
+        link: PathBuf,
+        target: PathBuf,
+    },
+    Unlink {
+        link: PathBuf,
+        target: PathBuf,
+    },
+    RemoveDir {
+        dir: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Copy {
+        path: PathBuf,
+        source: PathBuf,
+        hash: String,
+    },
+    Uncopy {
+        path: PathBuf,
+        source: PathBuf,
+        strategy: Strategy,
+        // where the copy went, older journals didn't keep it
+        backup: Option<PathBuf>,
+    },
+    Trash {
+        path: PathBuf,
+        trashed: PathBuf,
+    },
+}
+
+impl Action {
+    fn to_line(&self) -> String {
+        match self {
+            Action::Link { link, target } => {
+                format!("link\t{}\t{}\n", link.display(), target.display())
+            }
+            Action::Unlink { link, target } => {
+                format!("unlink\t{}\t{}\n", link.display(), target.display())
+            }
+            Action::RemoveDir { dir } => format!("rmdir\t{}\n", dir.display()),
+            Action::Rename { from, to } => {
+                format!("rename\t{}\t{}\n", from.display(), to.display())
+            }
+            Action::Copy { path, source, hash } => {
+                format!("copy\t{}\t{}\t{}\n", path.display(), source.display(), hash)
+            }
+            Action::Uncopy {
+                path,
+                source,
+                strategy,
+                backup,
+            } => format!(
+                "uncopy\t{}\t{}\t{}{}\n",
+                path.display(),
+                source.display(),
+                strategy.name(),
+                backup
+                    .as_ref()
+                    .map(|backup| format!("\t{}", backup.display()))
+                    .unwrap_or_default()
+            ),
+            Action::Trash { path, trashed } => {
+                format!("trash\t{}\t{}\n", path.display(), trashed.display())
+            }
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Action> {
+        let mut fields = line.split('\t');
+        let kind = fields.next()?;
+        let mut field = || fields.next();
+        let mut path = || field().map(PathBuf::from);
+
+        Some(match kind {
+            "link" => Action::Link {
+                link: path()?,
+                target: path()?,
+            },
+            "unlink" => Action::Unlink {
+                link: path()?,
+                target: path()?,
+            },
+            "rmdir" => Action::RemoveDir { dir: path()? },
+            "rename" => Action::Rename {
+                from: path()?,
+                to: path()?,
+            },
+            "copy" => Action::Copy {
+                path: path()?,
+                source: path()?,
+                hash: field()?.to_string(),
+            },
+            "uncopy" => Action::Uncopy {
+                path: path()?,
+                source: path()?,
+                strategy: field().and_then(Strategy::parse).unwrap_or(Strategy::Copy),
+                backup: field().map(PathBuf::from),
+            },
+            "trash" => Action::Trash {
+                path: path()?,
+                trashed: path()?,
+            },
+            _ => return None,
+        })
+    }
+}
+
+pub struct Journal {
+    dir: PathBuf,
+    run_id: String,
+    file: RefCell<Option<File>>,
+}
+
+impl Journal {
+    pub fn new(state_dir: &Path) -> Journal {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards");
+
+        Journal {
+            dir: state_dir.join(JOURNAL_DIR),
+            run_id: format!("{}.{:09}", now.as_secs(), now.subsec_nanos()),
+            file: RefCell::new(None),
+        }
+    }
+
+    // names this run's journal, and its backups
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+}
+
+// Every run still on record, oldest first, with what it did
+pub fn runs(ctx: &Context) -> Result<Vec<(String, Vec<Action>)>, Error> {
+    let mut journals: Vec<PathBuf> = read_dir(&ctx.journal.dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    journals.sort();
+
+    journals
+        .into_iter()
+        .filter_map(|journal| {
+            let run_id = journal.file_name()?.to_str()?.to_string();
+            Some(
+                read_to_string(&journal)
+                    .map_err(on(&journal))
+                    .map(|contents| {
+                        (
+                            run_id,
+                            contents.lines().filter_map(Action::from_line).collect(),
+                        )
+                    }),
+            )
+        })
+        .collect()
+}
+
+pub fn record(ctx: &Context, action: Action) -> Result<(), Error> {
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    let journal = &ctx.journal;
+    let path = journal.dir.join(&journal.run_id);
+    let mut file = journal.file.borrow_mut();
+
+    if file.is_none() {
+        create_dir_all(&journal.dir).map_err(on(&journal.dir))?;
+        *file = Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(on(&path))?,
+        );
+    }
+
+    file.as_mut()
+        .expect("opened above")
+        .write_all(action.to_line().as_bytes())
+        .map_err(on(&path))
+}
+
+pub fn undo(ctx: &Context) -> Result<(), Error> {
+    let latest = read_dir(&ctx.journal.dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .max();
+
+    let journal = match latest {
+        Some(journal) => journal,
+        None => {
+            ctx.message()
+                .with_verb("undo")
+                .warning(Some(String::from("nothing to undo")));
+            return Ok(());
+        }
+    };
+
+    let contents = read_to_string(&journal).map_err(on(&journal))?;
+
+    for action in contents.lines().rev().filter_map(Action::from_line) {
+        reverse(ctx, action)?;
+    }
+
+    if !ctx.dry_run {
+        remove_file(&journal).map_err(on(&journal))?;
+    }
+
+    manifest::save(ctx)
+}
+
+fn reverse(ctx: &Context, action: Action) -> Result<(), Error> {
+    match action {
+        Action::Link { link, target } => {
+            if resolve_link(&link).is_ok_and(|t| t == target) {
+                report(ctx, "unlink%", "", &link, None);
+                if !ctx.dry_run {
+                    remove_file(&link).map_err(on(&link))?;
+                    manifest::forget(ctx, &link);
+                }
+            }
+        }
+        Action::Unlink { link, target } => {
+            if link.symlink_metadata().is_err() {
+                report(ctx, "relink%", "", &link, Some(&target));
+                if !ctx.dry_run {
+                    let parent = link.parent().expect("What is this at the root?");
+                    create_dir_all(parent).map_err(on(parent))?;
+                    make_symlink(ctx, &target, &link)?;
+                    manifest::record(ctx, &link, &target);
+                }
+            }
+        }
+        Action::RemoveDir { dir } => {
+            if dir.symlink_metadata().is_err() {
+                report(ctx, "recreat% directory", "e", &dir, None);
+                if !ctx.dry_run {
+                    create_dir_all(&dir).map_err(on(&dir))?;
+                }
+            }
+        }
+        Action::Rename { from, to } => {
+            if from.symlink_metadata().is_err() && to.symlink_metadata().is_ok() {
+                report(ctx, "restor%", "e", &from, Some(&to));
+                if !ctx.dry_run {
+                    let parent = from.parent().expect("What is this at the root?");
+                    create_dir_all(parent).map_err(on(parent))?;
+                    rename(&to, &from).map_err(on(&to))?;
+                }
+            }
+        }
+        Action::Copy { path, hash, .. } => {
+            if !path.is_symlink() && content_hash(&path).is_ok_and(|h| h == hash) {
+                report(ctx, "remov% copy", "e", &path, None);
+                if !ctx.dry_run {
+                    remove_file(&path).map_err(on(&path))?;
+                    manifest::forget(ctx, &path);
+                }
+            }
+        }
+        Action::Trash { path, trashed } => {
+            if path.symlink_metadata().is_err() && trashed.symlink_metadata().is_ok() {
+                report(ctx, "untrash%", "", &path, Some(&trashed));
+                if !ctx.dry_run {
+                    let parent = path.parent().expect("What is this at the root?");
+                    create_dir_all(parent).map_err(on(parent))?;
+                    trash::take_back(&trashed, &path)?;
+                }
+            }
+        }
+        Action::Uncopy {
+            path,
+            source,
+            strategy,
+            backup,
+        } => {
+            if path.symlink_metadata().is_ok() {
+                return Ok(());
+            }
+
+            // the copy as it was, the source may have moved on since
+            if let Some(backup) = backup.filter(|backup| backup.is_file()) {
+                report(ctx, "restor%", "e", &path, Some(&backup));
+                if !ctx.dry_run {
+                    let parent = path.parent().expect("What is this at the root?");
+                    create_dir_all(parent).map_err(on(parent))?;
+                    rename(&backup, &path).map_err(on(&backup))?;
+                    let hash = content_hash(&path).map_err(on(&path))?;
+                    manifest::record_copy(ctx, &path, &source, strategy, hash);
+                }
+            } else if !source.is_file() {
+                ctx.message()
+                    .with_verb("skipping")
+                    .with_path(&path)
+                    .with_target_shown(&source)
+                    .warning(Some(String::from("can't recopy, it's gone from the repo")));
+            } else {
+                report(ctx, "recopy%", "", &path, Some(&source));
+                if !ctx.dry_run {
+                    write_copy(ctx, &path, &source, strategy)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn report(
+    ctx: &Context,
+    verb_template: &str,
+    base_ending: &str,
+    path: &Path,
+    other: Option<&Path>,
+) {
+    let message = ctx.message();
+    let message = if ctx.dry_run {
+        message.with_verb(&format!(
+            "would {}",
+            conjugate_with(verb_template, base_ending)
+        ))
+    } else {
+        message.with_verb(verb_template).conjugate_with("ing")
+    };
+
+    let message = message.with_path(path);
+    match other {
+        Some(other) => message.with_target_shown(other),
+        None => message,
+    }
+    .success(None);
+}
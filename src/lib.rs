@@ -1,117 +1,252 @@
-use once_cell::sync::OnceCell;
-use std::env;
-use std::error::Error;
-use std::fs::canonicalize;
-use std::path::PathBuf;
-use std::process::exit;
-use std::sync::Mutex;
-
+use std::io::{stdin, stdout, BufRead, Write};
+use std::path::Path;
+
+mod adopt;
+mod answers;
+mod backup;
+mod config;
+mod context;
 mod delete;
+mod diff;
+mod error;
 mod fs;
+mod ignores;
 mod install;
+mod journal;
+mod manifest;
 mod messages;
-
+mod plan;
+mod status;
+mod template;
+mod trash;
+
+pub use answers::Answers;
+pub use context::{Context, ContextOptions, Selector, This};
+pub use error::Error;
+pub use messages::Format;
 use messages::Messenger;
 
-const COMMANDS: [&str; 5] = ["install", "cleanup", "sync", "autocleanup", "implode"];
+const COMMANDS: [&str; 10] = [
+    "install",
+    "cleanup",
+    "sync",
+    "autocleanup",
+    "implode",
+    "status",
+    "adopt",
+    "undo",
+    "backups",
+    "diff",
+];
+const FLAGS: [&str; 6] = [
+    "--dry-run",
+    "--machine",
+    "--platform",
+    "--yes",
+    "--no",
+    "--trash",
+];
+const VALUE_FLAGS: [&str; 6] = [
+    "--root",
+    "--home",
+    "--hostname",
+    "--answer-file",
+    "--format",
+    "--tags",
+];
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    run_with_input(args, Box::new(stdin().lock()))
+}
 
-pub fn run(args: &Vec<String>) -> Result<(), Box<dyn Error>> {
-    let name = &args[0];
+// Same as run, but prompts are answered from input instead of stdin
+pub fn run_with_input(args: &[String], input: Box<dyn BufRead>) -> Result<(), Error> {
+    let name = match args.first() {
+        Some(name) => name,
+        None => return Err(Error::Usage(String::from(env!("CARGO_PKG_NAME")))),
+    };
+    let usage_error = || Error::Usage(name.to_string());
+
+    let mut flags: Vec<&str> = vec![];
+    let mut words: Vec<&str> = vec![];
+    let mut options = ContextOptions::default();
+    let mut answer_file = None;
+    let mut format = None;
+
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with("--") {
+            words.push(arg);
+            continue;
+        }
 
-    if args.len() < 2 {
-        usage(&name);
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (arg.as_str(), None),
+        };
+
+        if VALUE_FLAGS.contains(&flag) {
+            let value = match value {
+                Some(value) => value,
+                None => rest.next().ok_or_else(usage_error)?,
+            };
+            let slot = match flag {
+                "--root" => &mut options.root,
+                "--home" => &mut options.home,
+                "--tags" => &mut options.tags,
+                "--answer-file" => &mut answer_file,
+                "--format" => &mut format,
+                _ => &mut options.hostname,
+            };
+            *slot = Some(value.to_string());
+        } else if FLAGS.contains(&flag) && value.is_none() {
+            flags.push(flag);
+        } else {
+            return Err(usage_error());
+        }
     }
 
-    let input = &args[1].to_lowercase();
-
-    let command = COMMANDS.iter().find(|&command| command == &input.as_str());
-
-    DELETE_ALL.set(Mutex::new(false)).unwrap();
-
-    match command {
-        Some(selection) => match selection {
-            &"install" => install::run(),
-            &"cleanup" => delete::run(false, false),
-            &"autocleanup" => delete::run(false, true),
-            &"implode" => delete::run(true, false),
-            &"sync" => {
-                install::run()?;
-                delete::run(false, false)
+    let has_flag = |name: &str| flags.contains(&name);
+
+    let command = match words.first().and_then(|input| {
+        COMMANDS
+            .iter()
+            .find(|&command| command.eq_ignore_ascii_case(input))
+    }) {
+        Some(command) => *command,
+        None => return Err(usage_error()),
+    };
+    let operands = &words[1..];
+
+    options.dry_run = has_flag("--dry-run");
+    options.trash = has_flag("--trash");
+    options.format = match format {
+        Some(name) => Format::parse(&name).ok_or_else(usage_error)?,
+        None => Format::Text,
+    };
+
+    let fallback = match (has_flag("--yes"), has_flag("--no")) {
+        (true, false) => Some('y'),
+        (false, true) => Some('n'),
+        (false, false) => None,
+        (true, true) => return Err(usage_error()),
+    };
+    options.answers = match answer_file {
+        Some(path) => Answers::from_file(Path::new(&path), fallback)?,
+        None => fallback.map(Answers::always).unwrap_or_default(),
+    };
+
+    let ctx = &Context::new(options, input)?;
+
+    let result = match command {
+        "adopt" if operands.len() == 1 => adopt::run(
+            ctx,
+            operands[0],
+            match (has_flag("--machine"), has_flag("--platform")) {
+                (true, false) => Some(adopt::Label::Machine),
+                (false, true) => Some(adopt::Label::Platform),
+                (false, false) => None,
+                (true, true) => return Err(usage_error()),
+            },
+        ),
+        "diff" if operands.len() <= 1 => diff::run(ctx, operands.first().copied()),
+        "backups" => match operands {
+            [] => backup::list(ctx),
+            ["restore", run_id] => backup::restore(ctx, run_id, None),
+            ["restore", run_id, path] => backup::restore(ctx, run_id, Some(path)),
+            _ => Err(usage_error()),
+        },
+        selection if operands.is_empty() => match selection {
+            "install" => install::run(ctx),
+            "cleanup" => delete::run(ctx, false, false),
+            "autocleanup" => delete::run(ctx, false, true),
+            "implode" => delete::run(ctx, true, false),
+            "sync" => {
+                install::run(ctx)?;
+                delete::run(ctx, false, false)
             }
-            _ => Ok(usage(&name)),
+            "status" => status::run(ctx),
+            "undo" => journal::undo(ctx),
+            _ => Err(usage_error()),
         },
-        None => Ok(usage(&name)),
-    }
-}
-
-static HOME: OnceCell<PathBuf> = OnceCell::new();
-pub fn get_home() -> &'static PathBuf {
-    HOME.get_or_init(|| canonicalize_or_bust(&get_env_or_bust("HOME")))
-}
-
-static LINKOMATIC_ROOT: OnceCell<PathBuf> = OnceCell::new();
-pub fn get_root() -> &'static PathBuf {
-    LINKOMATIC_ROOT.get_or_init(|| canonicalize_or_bust(&get_env_or_bust("LINKOMATIC_ROOT")))
-}
+        _ => Err(usage_error()),
+    };
 
-static DELETE_ALL: OnceCell<Mutex<bool>> = OnceCell::new();
-pub fn get_delete_all() -> &'static Mutex<bool> {
-    DELETE_ALL.get().unwrap()
+    result.and(ctx.output_failure().map_or(Ok(()), Err))
 }
 
-#[derive(Debug)]
-pub struct This {
-    platform: String,
-    machine: String,
-}
-static THIS: OnceCell<This> = OnceCell::new();
-pub fn get_this() -> &'static This {
-    THIS.get_or_init(|| {
-        let machine = get_env_or_bust("LINKOMATIC_HOSTNAME");
-
-        let platform = match std::env::consts::OS {
-            "linux" => "linux",
-            "macos" => "mac",
-            _ => "unknown",
+pub fn report(error: &Error, format: Format) {
+    let messenger = Messenger::new(None, format);
+
+    match error {
+        // the usage text would break one object per line
+        Error::Usage(_) if format == Format::Json => messenger
+            .message()
+            .with_verb("usage")
+            .error(Some(error.to_string())),
+        Error::Usage(cmd) => usage(cmd),
+        Error::UserQuit => messenger
+            .message()
+            .with_verb("quitting")
+            .log(Some(String::from("per user"))),
+        Error::OutOfSync { .. } => messenger
+            .message()
+            .with_verb("status")
+            .warning(Some(error.to_string())),
+        Error::Collisions(collisions) => {
+            for (link, sources) in collisions {
+                messenger
+                    .message()
+                    .with_verb("collision")
+                    .with_path(link)
+                    .error(Some(format!(
+                        "wanted by {}",
+                        sources
+                            .iter()
+                            .map(|source| source.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )));
+            }
         }
-        .into();
-
-        This { platform, machine }
-    })
-}
-
-fn get_env_or_bust(name: &str) -> String {
-    env::var(name).unwrap_or_else(|err| {
-        Messenger::new().with_verb("Error").error(Some(format!(
-            "reading {} environment variable: {:?}",
-            name, err
-        )));
-        exit(1);
-    })
-}
-
-fn canonicalize_or_bust(name: &String) -> PathBuf {
-    canonicalize(name).unwrap_or_else(|err| {
-        Messenger::new().with_verb("Error").error(Some(format!(
-            "canonicalizing {} environment variable: {:?}",
-            name, err
-        )));
-        exit(1);
-    })
+        _ => messenger.message().error(Some(error.to_string())),
+    }
 }
 
+// a closed stdout is nothing to panic over this late
 fn usage(cmd: &str) {
-    println!(
+    let _ = writeln!(
+        stdout(),
         "
 link_o_matic v{}
 
-Usage: {} <command>
+Usage: {} [{}] [--root <dir>] [--home <dir>] [--hostname <name>] [--tags <a,b>]
+       [--yes|--no] [--answer-file <file>] [--format text|json] [--trash] <command>
+       {} [--dry-run] [--machine|--platform] adopt <dot file>
+       {} [--dry-run] backups [restore <run id> [<path>]]
+       {} diff [<dot file>]
 
 Commands: {}
+
+--root, --home, --hostname and --tags default to the LINKOMATIC_ROOT, HOME,
+LINKOMATIC_HOSTNAME and LINKOMATIC_TAGS environment variables.
+
+--yes and --no answer every prompt. --answer-file answers prompts per
+question, one `question = y|n` per line, for the questions
+{} and * for anything else.
+
+--trash moves what would be deleted or backed up to the freedesktop trash.
+
+--format json prints one JSON object per line instead of text, with the
+keys level, verb, path, target and message.
 ",
         env!("CARGO_PKG_VERSION"),
         cmd,
-        COMMANDS.join(" ")
+        FLAGS[0],
+        cmd,
+        cmd,
+        cmd,
+        COMMANDS.join(" "),
+        answers::QUESTIONS.join(" ")
     );
-    exit(1);
 }
@@ -5,7 +5,7 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     if let Err(e) = link_o_matic::run(&args) {
-        eprintln!("Unexpected: {:?}", e);
-        process::exit(2)
+        link_o_matic::report(&e, link_o_matic::Format::from_args(&args));
+        process::exit(e.exit_code())
     }
 }
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::on,
+    fs::{content_hash, Strategy},
+    Context, Error,
+};
+
+const MANIFEST_FILE: &str = "manifest";
+
+// Every link we have made, so cleanup can find links whose source
+// directory is gone and would otherwise never be scanned
+#[derive(Debug, Default)]
+pub struct Manifest {
+    path: PathBuf,
+    links: BTreeMap<PathBuf, Managed>,
+    changed: bool,
+}
+
+// A copy or generated file also keeps the hash of what was written, so
+// we can tell whether it has been edited since
+#[derive(Debug, Clone, PartialEq)]
+pub struct Managed {
+    pub target: PathBuf,
+    pub hash: Option<String>,
+    pub strategy: Strategy,
+}
+
+impl Manifest {
+    pub fn load(state_dir: &Path) -> Manifest {
+        let mut manifest = Manifest {
+            path: state_dir.join(MANIFEST_FILE),
+            ..Manifest::default()
+        };
+
+        if let Ok(contents) = read_to_string(&manifest.path) {
+            for line in contents.lines() {
+                let mut fields = line.split('\t');
+                if let (Some(link), Some(target)) = (fields.next(), fields.next()) {
+                    let hash = fields.next().map(String::from);
+                    let strategy = match (&hash, fields.next()) {
+                        (None, _) => Strategy::Link,
+                        (Some(_), name) => name.and_then(Strategy::parse).unwrap_or(Strategy::Copy),
+                    };
+
+                    manifest.links.insert(
+                        PathBuf::from(link),
+                        Managed {
+                            target: PathBuf::from(target),
+                            hash,
+                            strategy,
+                        },
+                    );
+                }
+            }
+        }
+
+        manifest
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let contents: String = self
+            .links
+            .iter()
+            .filter_map(|(link, managed)| {
+                let (link, target) = (link.to_str()?, managed.target.to_str()?);
+                Some(match &managed.hash {
+                    Some(hash) => format!(
+                        "{}\t{}\t{}\t{}\n",
+                        link,
+                        target,
+                        hash,
+                        managed.strategy.name()
+                    ),
+                    None => format!("{}\t{}\n", link, target),
+                })
+            })
+            .collect();
+
+        let dir = self.path.parent().expect("manifest lives in the state dir");
+        create_dir_all(dir).map_err(on(dir))?;
+        write(&self.path, contents).map_err(on(&self.path))?;
+
+        self.changed = false;
+        Ok(())
+    }
+
+    fn insert(&mut self, link: &Path, managed: Managed) {
+        if self.links.get(link) != Some(&managed) {
+            self.links.insert(link.to_path_buf(), managed);
+            self.changed = true;
+        }
+    }
+}
+
+pub fn record(ctx: &Context, link: &Path, target: &Path) {
+    ctx.manifest.borrow_mut().insert(
+        link,
+        Managed {
+            target: target.to_path_buf(),
+            hash: None,
+            strategy: Strategy::Link,
+        },
+    );
+}
+
+pub fn record_copy(ctx: &Context, link: &Path, target: &Path, strategy: Strategy, hash: String) {
+    ctx.manifest.borrow_mut().insert(
+        link,
+        Managed {
+            target: target.to_path_buf(),
+            hash: Some(hash),
+            strategy,
+        },
+    );
+}
+
+pub fn forget(ctx: &Context, link: &Path) {
+    let mut manifest = ctx.manifest.borrow_mut();
+
+    if manifest.links.remove(link).is_some() {
+        manifest.changed = true;
+    }
+}
+
+pub fn links(ctx: &Context) -> Vec<(PathBuf, Managed)> {
+    ctx.manifest
+        .borrow()
+        .links
+        .iter()
+        .map(|(link, managed)| (link.clone(), managed.clone()))
+        .collect()
+}
+
+// a link install made, even one pointing outside LINKOMATIC_ROOT
+pub fn is_recorded_link(ctx: &Context, link: &Path, target: &Path) -> bool {
+    ctx.manifest
+        .borrow()
+        .links
+        .get(link)
+        .is_some_and(|managed| managed.hash.is_none() && managed.target == target)
+}
+
+// a copy install made, whether or not it was edited since
+pub fn is_recorded_copy(ctx: &Context, path: &Path) -> bool {
+    ctx.manifest
+        .borrow()
+        .links
+        .get(path)
+        .is_some_and(|managed| managed.hash.is_some())
+}
+
+// a copy we made that nobody has edited since, safe to replace or remove
+pub fn is_unmodified_copy(ctx: &Context, path: &Path) -> bool {
+    let recorded = match ctx.manifest.borrow().links.get(path) {
+        Some(Managed {
+            hash: Some(hash), ..
+        }) => hash.clone(),
+        _ => return false,
+    };
+
+    !path.is_symlink() && content_hash(path).is_ok_and(|hash| hash == recorded)
+}
+
+pub fn save(ctx: &Context) -> Result<(), Error> {
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    ctx.manifest.borrow_mut().save()
+}
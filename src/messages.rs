@@ -1,43 +1,56 @@
-use once_cell::sync::OnceCell;
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
 
-use std::io::{stderr, stdin, stdout, Write};
-use std::process::exit;
+use std::io::{self, stderr, stdout, Write};
 
 use owo_colors::{OwoColorize, Style};
 
-use crate::{delete::DeleteOptions, fs::get_dot_path};
+use crate::{delete::DeleteOptions, diff, Context, Error};
 
 pub struct MessageBuilder<'a> {
     log_level: LogLevel,
     path: Option<String>,
+    target: Option<String>,
+    target_shown: bool,
     verb: String,
     handle: &'a Messenger,
 }
 
 impl MessageBuilder<'_> {
-    pub fn with_path(mut self, path: &PathBuf) -> Self {
-        self.path = Some(relative_dot_file(path));
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(relative_dot_file(self.handle.home.as_deref(), path));
         self
     }
 
+    // the target key in json, text lines leave it out
+    pub fn with_target(mut self, target: &Path) -> Self {
+        self.target = Some(target.display().to_string());
+        self
+    }
+
+    // for when the target is what the text line is about too
+    pub fn with_target_shown(mut self, target: &Path) -> Self {
+        self.target_shown = true;
+        self.with_target(target)
+    }
+
     pub fn with_verb(mut self, verb: &str) -> Self {
         self.verb = verb.to_string();
         self
     }
 
     pub fn conjugate_with(mut self, ending: &str) -> Self {
-        self.verb = conjugate_with(&self.verb, &ending);
+        self.verb = conjugate_with(&self.verb, ending);
         self
     }
 
-    pub fn error(mut self, rest: Option<String>) -> String {
-        if self.verb.len() < 1 {
+    pub fn error(mut self, rest: Option<String>) {
+        if self.verb.is_empty() {
             self.verb = String::from("error")
         }
         self.log_level = LogLevel::Error;
         self.log(rest);
-        exit(1);
     }
 
     pub fn warning(mut self, rest: Option<String>) {
@@ -53,6 +66,11 @@ impl MessageBuilder<'_> {
     pub fn log(self, rest: Option<String>) {
         self.handle.log_message(self, rest);
     }
+
+    // a unified diff under the usual line, or as the message in json
+    pub fn diff(self, diff: &str) {
+        self.handle.log_diff(self, diff);
+    }
 }
 
 pub struct Messenger {
@@ -60,10 +78,49 @@ pub struct Messenger {
     success_style: Style,
     warning_style: Style,
     error_style: Style,
+    home: Option<PathBuf>,
+    format: Format,
+    // the first write that failed, say to a closed pipe, for the run to
+    // end with instead of a panic
+    failed: RefCell<Option<io::Error>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    // for reporting errors from a run that never got as far as a Context
+    pub fn from_args(args: &[String]) -> Format {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("--format") {
+                Some("") => args.next().map(|v| v.as_str()),
+                Some(rest) => rest.strip_prefix('='),
+                None => None,
+            };
+            if let Some(format) = value.and_then(Format::parse) {
+                return format;
+            }
+        }
+        Format::Text
+    }
 }
 
 impl Messenger {
-    fn init() -> Messenger {
+    // paths under home are shown relative to it
+    pub fn new(home: Option<PathBuf>, format: Format) -> Messenger {
         let style = Style::new();
 
         Messenger {
@@ -71,23 +128,67 @@ impl Messenger {
             success_style: style.blue(),
             warning_style: style.green(),
             error_style: style.red(),
+            home,
+            format,
+            failed: RefCell::new(None),
         }
     }
 
-    pub fn get_instance() -> &'static Messenger {
-        INSTANCE.get_or_init(|| Self::init())
+    pub fn take_failure(&self) -> Option<Error> {
+        self.failed.borrow_mut().take().map(Error::from)
     }
 
-    pub fn new<'a>() -> MessageBuilder<'a> {
+    fn write(&self, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.failed.borrow_mut().get_or_insert(e);
+        }
+    }
+
+    pub fn message(&self) -> MessageBuilder<'_> {
         MessageBuilder {
             log_level: LogLevel::Normal,
             path: None,
+            target: None,
+            target_shown: false,
             verb: String::new(),
-            handle: Self::get_instance(),
+            handle: self,
         }
     }
 
     fn log_message(&self, options: MessageBuilder, rest: Option<String>) {
+        let result = match self.format {
+            Format::Text => self.text_line(&options, rest),
+            Format::Json => json_line(&options, rest),
+        };
+
+        let bytes = result.as_bytes();
+
+        self.write(match options.log_level {
+            LogLevel::Error => stderr().write_all(bytes),
+            _ => stdout().write_all(bytes),
+        })
+    }
+
+    fn log_diff(&self, options: MessageBuilder, diff: &str) {
+        if self.format == Format::Json {
+            return self.log_message(options, Some(diff.to_string()));
+        }
+
+        let mut result = self.text_line(&options, None);
+        for line in diff.lines() {
+            let style = match line.chars().next() {
+                Some('+') if !line.starts_with("+++") => self.warning_style,
+                Some('-') if !line.starts_with("---") => self.error_style,
+                Some('@') => self.success_style,
+                _ => Style::new(),
+            };
+            writeln!(result, "{}", line.style(style)).expect("writing to a String");
+        }
+
+        self.write(stdout().write_all(result.as_bytes()))
+    }
+
+    fn text_line(&self, options: &MessageBuilder, rest: Option<String>) -> String {
         let styled_verb = options.verb.style(match options.log_level {
             LogLevel::Normal => self.normal_style,
             LogLevel::Warning => self.warning_style,
@@ -95,37 +196,73 @@ impl Messenger {
             LogLevel::Error => self.error_style,
         });
 
-        let result = format!(
+        format!(
             "{:>9} {}\n",
             styled_verb.bold(),
-            join_line([options.path, rest])
-        );
+            join_line([
+                options.path.clone(),
+                options.target.clone().filter(|_| options.target_shown),
+                rest
+            ])
+        )
+    }
+}
 
-        let bytes = result.as_bytes();
+// one object per line, every key always present
+fn json_line(options: &MessageBuilder, rest: Option<String>) -> String {
+    let level = match options.log_level {
+        LogLevel::Normal => "log",
+        LogLevel::Warning => "warning",
+        LogLevel::Success => "success",
+        LogLevel::Error => "error",
+    };
+
+    format!(
+        "{{\"level\":{},\"verb\":{},\"path\":{},\"target\":{},\"message\":{}}}\n",
+        json_string(Some(level)),
+        json_string(Some(&options.verb)),
+        json_string(options.path.as_deref()),
+        json_string(options.target.as_deref()),
+        json_string(rest.as_deref()),
+    )
+}
 
-        match options.log_level {
-            LogLevel::Error => stderr().write_all(bytes),
-            _ => stdout().write_all(bytes),
+fn json_string(value: Option<&str>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return String::from("null"),
+    };
+
+    let mut result = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => {
+                write!(result, "\\u{:04x}", c as u32).expect("writing to a String");
+            }
+            c => result.push(c),
         }
-        .expect("stdout works")
     }
+    result.push('"');
+    result
 }
 
-static INSTANCE: OnceCell<Messenger> = OnceCell::new();
-
-fn join_line(list: [Option<String>; 2]) -> String {
+fn join_line(list: [Option<String>; 3]) -> String {
     list.iter()
         .filter_map(|l| l.to_owned())
         .collect::<Vec<String>>()
         .join(" | ")
 }
 
-fn relative_dot_file(entry: &PathBuf) -> String {
-    entry
-        .to_path_buf()
-        .strip_prefix(&get_dot_path(None))
-        .map(|p| p.display().to_string())
-        .expect("to be able to parse this path.")
+fn relative_dot_file(home: Option<&Path>, entry: &Path) -> String {
+    home.and_then(|home| entry.strip_prefix(home).ok())
+        .unwrap_or(entry)
+        .display()
+        .to_string()
 }
 
 enum LogLevel {
@@ -136,19 +273,42 @@ enum LogLevel {
 }
 
 pub fn conjugate_with(template: &str, ending: &str) -> String {
-    if !template.contains('%') {
-        Messenger::new().error(Some(format!("template must contain a %")));
+    match template.split_once('%') {
+        Some((start, end)) => format!("{}{}{}", start, ending, end),
+        None => format!("{}{}", template, ending),
     }
-    let parts: Vec<&str> = template.split('%').collect();
-
-    format!("{}{}{}", parts[0], ending, parts[1])
 }
 
 const CHOICES: [&str; 4] = ["yes", "no", "all", "quit"];
 const DEFAULT_CHOICE: char = 'n';
+// what a prompt gives back when stdin has run out, a no that says why
+pub const NO_ANSWER: char = '\0';
+
+// The one event for a prompt answered no, or not answered at all
+pub fn log_skipping(ctx: &Context, path: &Path, answer: char) {
+    ctx.message().with_verb("skipping").with_path(path).warning(
+        (answer == NO_ANSWER)
+            .then(|| String::from("no answer on stdin (use --yes, --no or --answer-file)")),
+    );
+}
+
+fn choices(options: &DeleteOptions) -> Vec<&'static str> {
+    let mut choices = CHOICES.to_vec();
+    if options.can_merge {
+        choices.insert(2, "merge");
+    }
+    if options.can_pull {
+        choices.insert(2, "pull");
+    }
+    if options.diff_with.is_some() {
+        choices.insert(2, "diff");
+    }
+    choices
+}
 
-pub fn delete_prompt_help() {
-    let choices = CHOICES
+pub fn delete_prompt_help(ctx: &Context, options: &DeleteOptions) {
+    let choices = choices(options)
+        .iter()
         .map(|c| {
             format!(
                 "{} - {}",
@@ -156,43 +316,66 @@ pub fn delete_prompt_help() {
                 c
             )
         })
+        .collect::<Vec<_>>()
         .join(", ");
-    Messenger::new().with_verb("Choose:").warning(Some(choices))
+    ctx.message().with_verb("Choose:").warning(Some(choices))
 }
 
-pub fn display_delete_prompt(name: &PathBuf, options: &DeleteOptions) -> char {
+pub fn display_delete_prompt(
+    ctx: &Context,
+    name: &Path,
+    options: &DeleteOptions,
+) -> Result<char, Error> {
     let mut input = String::new();
 
-    print!(
+    let prompt = format!(
         "{:>9}{}{} ? [{}] ",
-        conjugate_with(&options.verb_template, &"e").bold(),
-        if name.is_symlink() { &" " } else { &" real " },
-        relative_dot_file(name),
-        CHOICES
+        conjugate_with(options.verb_template, "e").bold(),
+        if name.is_symlink() { " " } else { " real " },
+        relative_dot_file(Some(&ctx.home), name),
+        choices(options)
+            .iter()
             .map(|c| c.get(..1).expect("choices should have at least 1 letter"))
-            .join("")
+            .collect::<String>()
     );
 
-    stdout().flush().unwrap_or_else(|err| {
-        eprintln!("Problem flushing stdout: {:?}", err);
-        exit(1);
-    });
+    // keep stdout to one event per line when it's being parsed
+    if ctx.format == Format::Json {
+        let mut stderr = stderr();
+        stderr.write_all(prompt.as_bytes())?;
+        stderr.flush()?;
+    } else {
+        let mut stdout = stdout();
+        stdout.write_all(prompt.as_bytes())?;
+        stdout.flush()?;
+    }
 
-    stdin().read_line(&mut input).expect("stdin works");
+    if ctx.input.borrow_mut().read_line(&mut input)? == 0 {
+        return Ok(NO_ANSWER);
+    }
 
-    let result = input.trim().chars().nth(0).unwrap_or(DEFAULT_CHOICE);
+    let result = input.trim().chars().next().unwrap_or(DEFAULT_CHOICE);
 
     if result == 'q' {
-        Messenger::new()
-            .with_verb("quitting")
-            .log(Some(String::from("per user")));
-        exit(0);
-    } else if result == 'y' || result == 'a' {
-        return result;
+        return Err(Error::UserQuit);
+    } else if result == 'y'
+        || result == 'a'
+        || (result == 'm' && options.can_merge)
+        || (result == 'p' && options.can_pull)
+    {
+        return Ok(result);
     } else if result == '?' {
-        delete_prompt_help();
-        return display_delete_prompt(name, options);
+        delete_prompt_help(ctx, options);
+        return display_delete_prompt(ctx, name, options);
+    } else if let Some(entry) = options.diff_with.filter(|_| result == 'd') {
+        if !diff::show(ctx, entry)? {
+            ctx.message()
+                .with_verb("diff")
+                .with_path(name)
+                .log(Some(String::from("same as the repo")));
+        }
+        return display_delete_prompt(ctx, name, options);
     }
 
-    return DEFAULT_CHOICE;
+    Ok(DEFAULT_CHOICE)
 }
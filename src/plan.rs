@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::{
+    context::DEFAULT_LABEL,
+    fs::{find_targets_for_linking, is_invalid_to_target, longest_label, DotEntry},
+    Context, Error,
+};
+
+// How strongly a source claims its link name, the highest wins
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Default,
+    Platform,
+    // by where the tag is in the list, the first one is the strongest
+    Tag(Reverse<usize>),
+    Machine,
+    // unlabeled, or labeled for something that has a link name of its own
+    Own,
+}
+
+// One entry from the source directory and whether another variant won
+// its link name
+#[derive(Debug)]
+pub struct Planned {
+    pub entry: DotEntry,
+    pub source: PathBuf,
+    pub shadowed_by: Option<PathBuf>,
+}
+
+// Every entry install would handle, with at most one variant chosen for
+// each link name
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub entries: Vec<Planned>,
+    chosen: BTreeMap<PathBuf, usize>,
+}
+
+impl Plan {
+    pub fn chosen(&self, link: &Path) -> Option<&DotEntry> {
+        self.chosen
+            .get(link)
+            .map(|&index| &self.entries[index].entry)
+    }
+
+    pub fn source_for(&self, link: &Path) -> Option<&Path> {
+        self.chosen
+            .get(link)
+            .map(|&index| self.entries[index].source.as_path())
+    }
+}
+
+// Worked out once per run, cleanup needs the same answer install had,
+// and before anything is touched so collisions stop the run cleanly
+pub fn get(ctx: &Context) -> Result<Rc<Plan>, Error> {
+    if let Some(plan) = ctx.plan.borrow().as_ref() {
+        return Ok(Rc::clone(plan));
+    }
+
+    let plan = Rc::new(build(ctx)?);
+    *ctx.plan.borrow_mut() = Some(Rc::clone(&plan));
+    Ok(plan)
+}
+
+// after something new turns up in the source directory
+pub fn forget(ctx: &Context) {
+    ctx.plan.borrow_mut().take();
+}
+
+struct Claim {
+    link: PathBuf,
+    rank: Rank,
+    index: usize,
+    // a platform or tag file standing in for the machine's own
+    fallback: Option<DotEntry>,
+}
+
+fn build(ctx: &Context) -> Result<Plan, Error> {
+    let found = RefCell::new(vec![]);
+    find_targets_for_linking(ctx, &|source, entry| {
+        found.borrow_mut().push((source.to_path_buf(), entry));
+        Ok(())
+    })?;
+
+    let mut found = found.into_inner();
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut claims = vec![];
+    let mut entries = vec![];
+
+    for (index, (source, entry)) in found.into_iter().enumerate() {
+        if !is_invalid_to_target(ctx, &entry.target) {
+            let (rank, fallback) = rank_of(ctx, &source);
+
+            // in its own link name a tag or platform file has no rival
+            claims.push(Claim {
+                link: entry.link.clone(),
+                rank: match rank {
+                    Rank::Machine | Rank::Default => rank,
+                    _ => Rank::Own,
+                },
+                index,
+                fallback: None,
+            });
+
+            if let Some(link) = fallback.and_then(|link_side| machine_slot(&entry.link, link_side))
+            {
+                claims.push(Claim {
+                    fallback: Some(DotEntry {
+                        link: link.clone(),
+                        ..entry.clone()
+                    }),
+                    link,
+                    rank,
+                    index,
+                });
+            }
+        }
+
+        entries.push(Planned {
+            entry,
+            source,
+            shadowed_by: None,
+        });
+    }
+
+    // platform and tag files only stand in for the machine where a
+    // _default file says that link name is wanted everywhere
+    let defaulted: Vec<PathBuf> = claims
+        .iter()
+        .filter(|claim| claim.rank == Rank::Default)
+        .map(|claim| claim.link.clone())
+        .collect();
+    claims.retain(|claim| claim.fallback.is_none() || defaulted.contains(&claim.link));
+
+    let mut winners: BTreeMap<PathBuf, usize> = BTreeMap::new();
+    for (position, claim) in claims.iter().enumerate() {
+        match winners.get(&claim.link) {
+            Some(&best) if claims[best].rank >= claim.rank => continue,
+            _ => winners.insert(claim.link.clone(), position),
+        };
+    }
+
+    // two equally specific sources for the winning link, it's not ours to
+    // pick one
+    let mut collisions = vec![];
+    for (link, &winner) in &winners {
+        let sources: Vec<PathBuf> = claims
+            .iter()
+            .filter(|claim| &claim.link == link && claim.rank == claims[winner].rank)
+            .map(|claim| entries[claim.index].source.clone())
+            .collect();
+
+        if sources.len() > 1 {
+            collisions.push((link.clone(), sources));
+        }
+    }
+
+    if !collisions.is_empty() {
+        return Err(Error::Collisions(collisions));
+    }
+
+    for (position, claim) in claims.iter().enumerate() {
+        let winner = winners[&claim.link];
+
+        if claim.fallback.is_none() && winner != position {
+            entries[claim.index].shadowed_by = Some(entries[claims[winner].index].source.clone());
+        }
+    }
+
+    let mut chosen = BTreeMap::new();
+
+    for (link, position) in winners {
+        let claim = &mut claims[position];
+        let index = match claim.fallback.take() {
+            Some(entry) => {
+                entries.push(Planned {
+                    entry,
+                    source: entries[claim.index].source.clone(),
+                    shadowed_by: None,
+                });
+                entries.len() - 1
+            }
+            None => claim.index,
+        };
+        chosen.insert(link, index);
+    }
+
+    Ok(Plan { entries, chosen })
+}
+
+// machine beats tags, in the order they were given, beats platform beats
+// default, and a tag or platform file can stand in for the machine under
+// the returned link side
+fn rank_of<'a>(ctx: &'a Context, source: &Path) -> (Rank, Option<&'a str>) {
+    let name = source
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let this = &ctx.this;
+    let selectors = this.selectors();
+
+    let Some((selector, _)) = longest_label(&selectors, name, |selector| selector.value) else {
+        return (Rank::Own, None);
+    };
+
+    if selector.value == this.machine {
+        (Rank::Machine, None)
+    } else if selector.value == DEFAULT_LABEL {
+        (Rank::Default, None)
+    } else if let Some(index) = this.tags.iter().position(|tag| tag == selector.value) {
+        (Rank::Tag(Reverse(index)), Some(&this.tags[index]))
+    } else if selector.link_side == "platform" {
+        (Rank::Platform, Some("platform"))
+    } else {
+        (Rank::Own, None)
+    }
+}
+
+// `_<link_side>rest` as `_machinerest` in the same directory
+fn machine_slot(link: &Path, link_side: &str) -> Option<PathBuf> {
+    let rest = link
+        .file_name()?
+        .to_str()?
+        .strip_prefix(&format!("_{}", link_side))?;
+
+    Some(link.with_file_name(format!("_machine{}", rest)))
+}
@@ -0,0 +1,102 @@
+use std::cell::Cell;
+
+use crate::{
+    fs::{DotEntry, Strategy},
+    install::{link_state, LinkState},
+    plan, Context, Error,
+};
+
+pub fn run(ctx: &Context) -> Result<(), Error> {
+    let total = Cell::new(0);
+    let out_of_sync = Cell::new(0);
+
+    let report = |entry: &DotEntry| -> Result<(), Error> {
+        let message = ctx.message().with_path(&entry.link);
+
+        total.set(total.get() + 1);
+
+        let in_sync = match link_state(ctx, entry) {
+            LinkState::Linked => {
+                message
+                    .with_verb("linked")
+                    .with_target(&entry.target)
+                    .success(None);
+                true
+            }
+            LinkState::Copied => {
+                message
+                    .with_verb(match entry.strategy {
+                        Strategy::Template => "generated",
+                        _ => "copied",
+                    })
+                    .with_target(&entry.target)
+                    .success(None);
+                true
+            }
+            LinkState::Stale => {
+                message
+                    .with_verb("stale")
+                    .warning(Some(String::from("copy is out of date")));
+                false
+            }
+            LinkState::Modified => {
+                message
+                    .with_verb("modified")
+                    .warning(Some(String::from("differs from the repo")));
+                false
+            }
+            LinkState::Ignored => {
+                message.with_verb("ignored").log(None);
+                true
+            }
+            LinkState::Missing => {
+                message.with_verb("missing").warning(None);
+                false
+            }
+            LinkState::Broken(e) => {
+                message.with_verb("broken").warning(Some(e));
+                false
+            }
+            LinkState::Elsewhere { old_target, dead } => {
+                message
+                    .with_verb(if dead { "dead" } else { "elsewhere" })
+                    .with_target_shown(&old_target)
+                    .warning(None);
+                false
+            }
+            LinkState::Blocked => {
+                message
+                    .with_verb("blocked")
+                    .warning(Some(String::from("not a link")));
+                false
+            }
+        };
+
+        if !in_sync {
+            out_of_sync.set(out_of_sync.get() + 1);
+        }
+
+        Ok(())
+    };
+
+    for planned in &plan::get(ctx)?.entries {
+        match &planned.shadowed_by {
+            Some(winner) => ctx
+                .message()
+                .with_verb("shadowed")
+                .with_path(&planned.entry.link)
+                .with_target_shown(&planned.entry.target)
+                .log(Some(format!("by {}", winner.display()))),
+            None => report(&planned.entry)?,
+        }
+    }
+
+    if out_of_sync.get() > 0 {
+        return Err(Error::OutOfSync {
+            out_of_sync: out_of_sync.get(),
+            total: total.get(),
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{read, read_to_string};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::{error::on, fs::Strategy, Context, Error};
+
+pub const VARS_DIR: &str = "vars";
+
+// What a copied or generated entry should contain
+pub fn generate(ctx: &Context, source: &Path, strategy: Strategy) -> Result<Vec<u8>, Error> {
+    match strategy {
+        Strategy::Template => render(ctx, source).map(String::into_bytes),
+        _ => read(source).map_err(on(source)),
+    }
+}
+
+// Replaces every {{ name }} with machine, platform, arch, distro, env.NAME
+// or a value from the per-host vars file
+pub fn render(ctx: &Context, source: &Path) -> Result<String, Error> {
+    let template = read_to_string(source).map_err(on(source))?;
+    let error = |message: String| Error::Template {
+        path: source.to_path_buf(),
+        message,
+    };
+
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| error(String::from("{{ is never closed")))?;
+        let name = after[..end].trim();
+
+        result.push_str(
+            &lookup(ctx, name).ok_or_else(|| error(format!("unknown variable {}", name)))?,
+        );
+        rest = &after[end + 2..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn lookup(ctx: &Context, name: &str) -> Option<String> {
+    match name {
+        "machine" => Some(ctx.this.machine.clone()),
+        "platform" => Some(ctx.this.platform.clone()),
+        "arch" => Some(ctx.this.arch.clone()),
+        "distro" => ctx.this.distro.clone(),
+        _ => match name.strip_prefix("env.") {
+            Some(var) => env::var(var).ok(),
+            None => ctx.vars.get(name).cloned(),
+        },
+    }
+}
+
+// `ROOT/vars/<hostname>`, one `name = value` per line, missing is fine
+pub fn load_vars(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let contents = match read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(on(path)(e)),
+    };
+
+    let mut vars = BTreeMap::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = line.split_once('=').ok_or_else(|| Error::BadConfig {
+            path: path.to_path_buf(),
+            message: format!("line {}: expected name = value", number + 1),
+        })?;
+
+        vars.insert(name.trim().to_string(), value.trim().to_string());
+    }
+
+    Ok(vars)
+}
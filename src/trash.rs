@@ -0,0 +1,114 @@
+use std::fs::{create_dir_all, remove_file, rename, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{error::on, Error};
+
+// Moves path into the freedesktop trash as files/<name>, next to an
+// info/<name>.trashinfo saying where it came from, and returns where it went
+pub fn put(trash: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let files = trash.join("files");
+    let info = trash.join("info");
+    create_dir_all(&files).map_err(on(&files))?;
+    create_dir_all(&info).map_err(on(&info))?;
+
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::NotUtf8(path.to_path_buf()))?;
+
+    // creating the .trashinfo first is what claims the name
+    for number in 1.. {
+        let trashed_name = match number {
+            1 => name.to_string(),
+            _ => format!("{}.{}", name, number),
+        };
+        let info_file = info.join(format!("{}.trashinfo", trashed_name));
+        let trashed = files.join(&trashed_name);
+
+        if trashed.symlink_metadata().is_ok() {
+            continue;
+        }
+
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_file)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(on(&info_file)(e)),
+        };
+
+        file.write_all(
+            format!(
+                "[Trash Info]\nPath={}\nDeletionDate={}\n",
+                encode(path),
+                deletion_date()
+            )
+            .as_bytes(),
+        )
+        .map_err(on(&info_file))?;
+
+        if let Err(e) = rename(path, &trashed) {
+            let _ = remove_file(&info_file);
+            return Err(on(path)(e));
+        }
+
+        return Ok(trashed);
+    }
+
+    unreachable!("ran out of numbers")
+}
+
+// Puts a trashed path back and drops its .trashinfo
+pub fn take_back(trashed: &Path, path: &Path) -> Result<(), Error> {
+    rename(trashed, path).map_err(on(trashed))?;
+
+    if let (Some(trash), Some(name)) = (
+        trashed.parent().and_then(|files| files.parent()),
+        trashed.file_name(),
+    ) {
+        let mut info_name = name.to_os_string();
+        info_name.push(".trashinfo");
+        let _ = remove_file(trash.join("info").join(info_name));
+    }
+
+    Ok(())
+}
+
+// percent encoded like a URL path, as the spec asks
+fn encode(path: &Path) -> String {
+    path.to_string_lossy()
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// YYYY-MM-DDThh:mm:ss in local time, as the spec asks
+fn deletion_date() -> String {
+    // SAFETY: time and the _r conversions only write to what they're given
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            libc::gmtime_r(&now, &mut tm);
+        }
+        tm
+    };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
@@ -0,0 +1,1041 @@
+use std::fs::{create_dir_all, read_dir, read_link, read_to_string, remove_file, write};
+use std::io::Cursor;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use link_o_matic::{run_with_input, Error};
+use tempfile::TempDir;
+
+const HOSTNAME: &str = "bob";
+
+struct Dotfiles {
+    _dir: TempDir,
+    root: PathBuf,
+    home: PathBuf,
+}
+
+impl Dotfiles {
+    fn new() -> Dotfiles {
+        let dir = TempDir::new().expect("temp dir");
+        let root = dir.path().canonicalize().unwrap().join("dotfiles");
+        let home = dir.path().canonicalize().unwrap().join("home");
+        create_dir_all(root.join("home")).unwrap();
+        create_dir_all(&home).unwrap();
+
+        Dotfiles {
+            _dir: dir,
+            root,
+            home,
+        }
+    }
+
+    fn add(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.root.join(name);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, contents).unwrap();
+        path
+    }
+
+    fn run(&self, args: &[&str], answers: &str) -> Result<(), Error> {
+        let mut all: Vec<String> = vec![
+            "link_o_matic".into(),
+            "--root".into(),
+            self.root.display().to_string(),
+            "--home".into(),
+            self.home.display().to_string(),
+            "--hostname".into(),
+            HOSTNAME.into(),
+        ];
+        all.extend(args.iter().map(|a| a.to_string()));
+
+        run_with_input(&all, Box::new(Cursor::new(answers.to_string())))
+    }
+
+    fn home(&self, name: &str) -> PathBuf {
+        self.home.join(name)
+    }
+
+    // the binary itself, for what only shows in its output
+    fn bin(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_link_o_matic"));
+        command
+            .args(["--root", &self.root.display().to_string()])
+            .args(["--home", &self.home.display().to_string()])
+            .args(["--hostname", HOSTNAME])
+            .args(args);
+        command
+    }
+
+    // stdout of a --format json run of the binary
+    fn json(&self, args: &[&str]) -> String {
+        let output = self
+            .bin(&[&["--format", "json"], args].concat())
+            .output()
+            .unwrap();
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    // the latest backup of ~/name
+    fn backup(&self, name: &str) -> Option<PathBuf> {
+        let mut runs: Vec<PathBuf> = read_dir(self.home(".local/state/link_o_matic/backups"))
+            .ok()?
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        runs.sort();
+
+        runs.into_iter()
+            .rev()
+            .map(|run| run.join(name))
+            .find(|backup| backup.symlink_metadata().is_ok())
+    }
+}
+
+fn platform() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "mac",
+        "linux" => "linux",
+        _ => "unknown",
+    }
+}
+
+fn points_to(link: &Path, target: &Path) -> bool {
+    read_link(link).is_ok_and(|t| t == target)
+}
+
+#[test]
+fn install_links_files_and_nested_directories() {
+    let dots = Dotfiles::new();
+    let vimrc = dots.add("home/vimrc", "set nu");
+    let fish = dots.add("home/config/fish/config.fish", "set -x");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(points_to(&dots.home(".vimrc"), &vimrc));
+    assert!(points_to(&dots.home(".config/fish/config.fish"), &fish));
+}
+
+#[test]
+fn install_renames_machine_and_platform_labels() {
+    let dots = Dotfiles::new();
+    let machine = dots.add("home/config/fish/_bob.fish", "machine");
+    let platform_file = dots.add(
+        &format!("home/config/fish/_{}.fish", platform()),
+        "platform",
+    );
+    dots.add("home/config/fish/_alice.fish", "someone else");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(points_to(
+        &dots.home(".config/fish/_machine.fish"),
+        &machine
+    ));
+    assert!(points_to(
+        &dots.home(".config/fish/_platform.fish"),
+        &platform_file
+    ));
+    assert!(!dots.home(".config/fish/_alice.fish").exists());
+}
+
+#[test]
+fn install_follows_relative_alias_symlinks() {
+    let dots = Dotfiles::new();
+    let init = dots.add("config/nvim/init.vim", "set nu");
+    symlink("../config/nvim/init.vim", dots.root.join("home/vimrc")).unwrap();
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(points_to(&dots.home(".vimrc"), &init));
+}
+
+#[test]
+fn install_backs_up_real_files_when_told_to() {
+    let dots = Dotfiles::new();
+    let vimrc = dots.add("home/vimrc", "from repo");
+    write(dots.home(".vimrc"), "hand edited").unwrap();
+
+    dots.run(&["install"], "n\n").unwrap();
+    assert_eq!(read_to_string(dots.home(".vimrc")).unwrap(), "hand edited");
+
+    dots.run(&["install"], "y\n").unwrap();
+    assert!(points_to(&dots.home(".vimrc"), &vimrc));
+    assert_eq!(
+        read_to_string(dots.backup(".vimrc").unwrap()).unwrap(),
+        "hand edited"
+    );
+    assert!(!dots.home(".vimrc.bak").exists());
+}
+
+#[test]
+fn cleanup_removes_orphaned_links_and_empty_directories() {
+    let dots = Dotfiles::new();
+    dots.add("home/config/keep", "keep");
+    let gone = dots.add("home/config/gone/thing", "gone");
+
+    dots.run(&["install"], "").unwrap();
+    remove_file(&gone).unwrap();
+
+    dots.run(&["cleanup"], "y\ny\n").unwrap();
+
+    assert!(!dots.home(".config/gone/thing").is_symlink());
+    assert!(!dots.home(".config/gone").exists());
+    assert!(dots.home(".config/keep").is_symlink());
+}
+
+#[test]
+fn cleanup_keeps_links_when_answered_no() {
+    let dots = Dotfiles::new();
+    let gone = dots.add("home/config/gone", "gone");
+
+    dots.run(&["install"], "").unwrap();
+    remove_file(&gone).unwrap();
+
+    dots.run(&["cleanup"], "n\n").unwrap();
+
+    assert!(dots.home(".config/gone").is_symlink());
+}
+
+#[test]
+fn autocleanup_removes_orphans_without_prompting() {
+    let dots = Dotfiles::new();
+    let gone = dots.add("home/config/gone", "gone");
+
+    dots.run(&["install"], "").unwrap();
+    remove_file(&gone).unwrap();
+
+    dots.run(&["autocleanup"], "").unwrap();
+
+    assert!(!dots.home(".config/gone").is_symlink());
+}
+
+#[test]
+fn cleanup_finds_orphans_under_removed_top_level_directories() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "keep");
+    dots.add("home/config/gone/thing", "gone");
+
+    dots.run(&["install"], "").unwrap();
+    std::fs::remove_dir_all(dots.root.join("home/config")).unwrap();
+
+    dots.run(&["autocleanup"], "").unwrap();
+
+    assert!(!dots.home(".config/gone/thing").is_symlink());
+    assert!(dots.home(".vimrc").is_symlink());
+}
+
+#[test]
+fn sync_installs_then_cleans_up() {
+    let dots = Dotfiles::new();
+    let gone = dots.add("home/config/gone", "gone");
+    dots.run(&["install"], "").unwrap();
+    remove_file(&gone).unwrap();
+    let vimrc = dots.add("home/vimrc", "new");
+
+    dots.run(&["sync"], "y\n").unwrap();
+
+    assert!(points_to(&dots.home(".vimrc"), &vimrc));
+    assert!(!dots.home(".config/gone").is_symlink());
+}
+
+#[test]
+fn implode_removes_every_link() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    dots.add("home/config/fish/config.fish", "fish");
+    dots.run(&["install"], "").unwrap();
+
+    dots.run(&["implode"], "a\n").unwrap();
+
+    assert!(!dots.home(".vimrc").is_symlink());
+    assert!(!dots.home(".config/fish/config.fish").is_symlink());
+    assert!(!dots.home(".config/fish").exists());
+}
+
+#[test]
+fn implode_stops_when_user_quits() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    dots.run(&["install"], "").unwrap();
+
+    assert!(matches!(
+        dots.run(&["implode"], "q\n"),
+        Err(Error::UserQuit)
+    ));
+    assert!(dots.home(".vimrc").is_symlink());
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    write(dots.home(".bashrc"), "real").unwrap();
+    dots.add("home/bashrc", "repo");
+
+    dots.run(&["--dry-run", "sync"], "").unwrap();
+
+    assert!(!dots.home(".vimrc").exists());
+    assert_eq!(read_to_string(dots.home(".bashrc")).unwrap(), "real");
+    assert!(!dots.home(".local/state").exists());
+}
+
+#[test]
+fn undo_reverses_the_last_run() {
+    let dots = Dotfiles::new();
+    let vimrc = dots.add("home/vimrc", "vim");
+    dots.run(&["install"], "").unwrap();
+    dots.run(&["implode"], "a\n").unwrap();
+
+    dots.run(&["undo"], "").unwrap();
+    assert!(points_to(&dots.home(".vimrc"), &vimrc));
+
+    dots.run(&["undo"], "").unwrap();
+    assert!(!dots.home(".vimrc").is_symlink());
+}
+
+#[test]
+fn status_reports_out_of_sync_links() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+
+    assert!(matches!(
+        dots.run(&["status"], ""),
+        Err(Error::OutOfSync {
+            out_of_sync: 1,
+            total: 1
+        })
+    ));
+
+    dots.run(&["install"], "").unwrap();
+    dots.run(&["status"], "").unwrap();
+}
+
+#[test]
+fn adopt_moves_a_dot_file_into_the_repo() {
+    let dots = Dotfiles::new();
+    create_dir_all(dots.home(".config/fish")).unwrap();
+    write(dots.home(".config/fish/extra.fish"), "extra").unwrap();
+    let path = dots.home(".config/fish/extra.fish").display().to_string();
+
+    dots.run(&["--machine", "adopt", &path], "").unwrap();
+
+    let source = dots.root.join("home/config/fish/_bob.extra.fish");
+    assert_eq!(read_to_string(&source).unwrap(), "extra");
+    assert!(points_to(
+        &dots.home(".config/fish/_machine.extra.fish"),
+        &source
+    ));
+}
+
+#[test]
+fn adopt_skips_home_itself() {
+    let dots = Dotfiles::new();
+    dots.add("link_o_matic.toml", "dot_prefix = false\n");
+    write(dots.home("notes"), "notes").unwrap();
+    let home = dots.home.display().to_string();
+
+    dots.run(&["--machine", "adopt", &home], "").unwrap();
+    dots.run(&["adopt", &home], "").unwrap();
+
+    assert!(dots.home.is_dir() && !dots.home.is_symlink());
+    assert!(dots.home("notes").is_file());
+    assert!(!dots.root.join("home/notes").exists());
+}
+
+#[test]
+fn aliases_inside_a_folded_directory_are_left_in_the_repo() {
+    let dots = Dotfiles::new();
+    dots.add("home/foo/.linkomatic-fold", "");
+    let real = dots.add("home/foo/real", "real");
+    let alias = dots.root.join("home/foo/alias");
+    symlink(&real, &alias).unwrap();
+
+    dots.run(&["install"], "").unwrap();
+    dots.run(&["autocleanup"], "").unwrap();
+
+    assert!(alias.is_symlink());
+    assert!(points_to(&dots.home(".foo"), &dots.root.join("home/foo")));
+
+    dots.run(&["implode"], "n\ny\n").unwrap();
+    assert!(alias.is_symlink());
+}
+
+#[test]
+fn adopted_directories_stay_linked_after_sync() {
+    let dots = Dotfiles::new();
+    create_dir_all(dots.home(".foo")).unwrap();
+    write(dots.home(".foo/a"), "a").unwrap();
+    write(dots.home(".foo/b"), "b").unwrap();
+    let path = dots.home(".foo").display().to_string();
+
+    dots.run(&["adopt", &path], "").unwrap();
+    dots.run(&["--yes", "sync"], "").unwrap();
+
+    let source = dots.root.join("home/foo");
+    assert!(points_to(&dots.home(".foo"), &source));
+    assert_eq!(read_to_string(dots.home(".foo/a")).unwrap(), "a");
+    dots.run(&["status"], "").unwrap();
+}
+
+#[test]
+fn unknown_commands_are_usage_errors() {
+    let dots = Dotfiles::new();
+
+    assert!(matches!(dots.run(&["explode"], ""), Err(Error::Usage(_))));
+    assert!(matches!(
+        dots.run(&["--bogus", "install"], ""),
+        Err(Error::Usage(_))
+    ));
+    assert!(matches!(
+        run_with_input(&[], Box::new(Cursor::new(""))),
+        Err(Error::Usage(_))
+    ));
+}
+
+#[test]
+fn a_closed_stdout_ends_the_run_with_an_error() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    let (reader, writer) = std::io::pipe().unwrap();
+    drop(reader);
+
+    let output = dots.bin(&["install"]).stdout(writer).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("panicked"));
+    assert!(dots.home(".vimrc").is_symlink());
+}
+
+#[test]
+fn an_unanswered_prompt_is_skipped_once() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    write(dots.home(".vimrc"), "mine").unwrap();
+
+    let skipped: Vec<String> = dots
+        .json(&["install"])
+        .lines()
+        .filter(|line| line.contains("\"verb\":\"skipping\""))
+        .map(String::from)
+        .collect();
+
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].contains("no answer on stdin"));
+    assert_eq!(read_to_string(dots.home(".vimrc")).unwrap(), "mine");
+}
+
+#[test]
+fn yes_answers_every_prompt() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    dots.run(&["install"], "").unwrap();
+
+    dots.run(&["--yes", "implode"], "").unwrap();
+
+    assert!(!dots.home(".vimrc").is_symlink());
+    assert!(matches!(
+        dots.run(&["--yes", "--no", "implode"], ""),
+        Err(Error::Usage(_))
+    ));
+}
+
+#[test]
+fn answer_file_answers_per_question() {
+    let dots = Dotfiles::new();
+    let answers = dots.add("answers", "# bootstrap\nbackup = n\ndelete = y\n* = n\n");
+    dots.add("home/bashrc", "repo");
+    write(dots.home(".bashrc"), "real").unwrap();
+    let gone = dots.add("home/config/gone", "gone");
+    dots.run(&["--no", "install"], "").unwrap();
+    remove_file(&gone).unwrap();
+
+    let file = answers.display().to_string();
+    dots.run(&["--answer-file", &file, "sync"], "").unwrap();
+
+    assert_eq!(read_to_string(dots.home(".bashrc")).unwrap(), "real");
+    assert!(!dots.home(".config/gone").is_symlink());
+    assert!(dots.home(".config").is_dir());
+}
+
+#[test]
+fn bad_answer_files_are_reported() {
+    let dots = Dotfiles::new();
+    let answers = dots.add("answers", "explode = y\n");
+
+    assert!(matches!(
+        dots.run(
+            &["--answer-file", &answers.display().to_string(), "sync"],
+            ""
+        ),
+        Err(Error::BadConfig { .. })
+    ));
+}
+
+#[test]
+fn json_format_prints_one_object_per_event() {
+    let dots = Dotfiles::new();
+    let vimrc = dots.add("home/vimrc", "vim");
+
+    let output = dots.bin(&["--format", "json", "install"]).output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{{\"level\":\"success\",\"verb\":\"linking\",\"path\":\".vimrc\",\"target\":\"{}\",\"message\":null}}\n",
+            vimrc.display()
+        )
+    );
+    assert!(matches!(
+        dots.run(&["--format", "yaml", "install"], ""),
+        Err(Error::Usage(_))
+    ));
+}
+
+#[test]
+fn targets_stay_out_of_text_lines_and_usage_errors_stay_json() {
+    let dots = Dotfiles::new();
+    let vimrc = dots.add("home/vimrc", "vim");
+
+    let output = dots.bin(&["install"]).output().unwrap();
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains(".vimrc"));
+    assert!(!text.contains(&vimrc.display().to_string()));
+
+    let output = dots.bin(&["--format", "json", "explode"]).output().unwrap();
+    assert!(output.stdout.is_empty());
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(error.lines().count(), 1);
+    assert!(error.starts_with("{\"level\":\"error\",\"verb\":\"usage\","));
+}
+
+#[test]
+fn copy_suffix_copies_and_keeps_copies_up_to_date() {
+    let dots = Dotfiles::new();
+    let config = dots.add("home/ssh/config.copy", "v1");
+    let config_link = dots.home(".ssh/config");
+
+    dots.run(&["install"], "").unwrap();
+    assert!(!config_link.is_symlink());
+    assert_eq!(read_to_string(&config_link).unwrap(), "v1");
+    dots.run(&["status"], "").unwrap();
+
+    write(&config, "v2").unwrap();
+    dots.run(&["install"], "").unwrap();
+    assert_eq!(read_to_string(&config_link).unwrap(), "v2");
+
+    write(&config_link, "edited").unwrap();
+    write(&config, "v3").unwrap();
+    dots.run(&["install"], "y\n").unwrap();
+    assert_eq!(read_to_string(&config_link).unwrap(), "v3");
+    assert_eq!(
+        read_to_string(dots.backup(".ssh/config").unwrap()).unwrap(),
+        "edited"
+    );
+}
+
+#[test]
+fn real_files_where_a_copy_goes_are_blocked() {
+    let dots = Dotfiles::new();
+    dots.add("home/ssh/config.copy", "repo");
+    create_dir_all(dots.home(".ssh")).unwrap();
+    write(dots.home(".ssh/config"), "mine").unwrap();
+
+    let status = dots.json(&["status"]);
+    assert!(status.contains("\"verb\":\"blocked\",\"path\":\".ssh/config\""));
+    assert!(!status.contains("modified"));
+}
+
+#[test]
+fn undo_puts_back_the_copy_an_update_replaced() {
+    let dots = Dotfiles::new();
+    let config = dots.add("home/ssh/config.copy", "v1");
+    dots.run(&["install"], "").unwrap();
+
+    write(&config, "v2").unwrap();
+    dots.run(&["install"], "").unwrap();
+    assert_eq!(read_to_string(dots.home(".ssh/config")).unwrap(), "v2");
+
+    dots.run(&["undo"], "").unwrap();
+    assert_eq!(read_to_string(dots.home(".ssh/config")).unwrap(), "v1");
+    assert!(dots.backup(".ssh/config").is_none());
+
+    write(&config, "v1").unwrap();
+    dots.run(&["status"], "").unwrap();
+}
+
+#[test]
+fn cleanup_removes_copies_whose_source_is_gone() {
+    let dots = Dotfiles::new();
+    let config = dots.add("home/ssh/config.copy", "v1");
+    dots.run(&["install"], "").unwrap();
+    remove_file(&config).unwrap();
+
+    dots.run(&["autocleanup"], "").unwrap();
+
+    assert!(!dots.home(".ssh/config").exists());
+    assert!(dots.backup(".ssh/config").is_none());
+}
+
+#[test]
+fn templates_are_rendered_with_host_variables() {
+    let dots = Dotfiles::new();
+    dots.add("vars/bob", "# bob's\nemail = bob@example.com\n");
+    let gitconfig = dots.add(
+        "home/gitconfig.tmpl",
+        "email = {{ email }}\n# {{ machine }} on {{platform}}\n",
+    );
+
+    dots.run(&["install"], "").unwrap();
+    assert_eq!(
+        read_to_string(dots.home(".gitconfig")).unwrap(),
+        format!("email = bob@example.com\n# bob on {}\n", platform())
+    );
+    dots.run(&["status"], "").unwrap();
+
+    write(&gitconfig, "{{ nobody }}").unwrap();
+    dots.run(&["install"], "").unwrap();
+    assert!(matches!(
+        dots.run(&["status"], ""),
+        Err(Error::OutOfSync { out_of_sync: 1, .. })
+    ));
+}
+
+#[test]
+fn config_file_changes_the_conventions() {
+    let dots = Dotfiles::new();
+    dots.add(
+        "link_o_matic.toml",
+        r#"
+source = "dots"
+dot_prefix = false
+ignore = ["*.md"]
+
+[labels]
+work = ["bob"]
+
+[strategies]
+"ssh/*" = "copy"
+"#,
+    );
+    let bin = dots.add("dots/bin/hello", "echo hi");
+    let work = dots.add("dots/_work.fish", "work");
+    dots.add("dots/README.md", "docs");
+    dots.add("dots/ssh/config", "Host *");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(points_to(&dots.home("bin/hello"), &bin));
+    assert!(points_to(&dots.home("_work.fish"), &work));
+    assert!(!dots.home("README.md").exists());
+    assert!(!dots.home("ssh/config").is_symlink());
+    assert_eq!(read_to_string(dots.home("ssh/config")).unwrap(), "Host *");
+}
+
+#[test]
+fn bad_config_files_are_reported() {
+    let dots = Dotfiles::new();
+    dots.add(
+        "link_o_matic.toml",
+        "[strategies]\n\"ssh/*\" = \"teleport\"\n",
+    );
+
+    assert!(matches!(
+        dots.run(&["install"], ""),
+        Err(Error::BadConfig { .. })
+    ));
+
+    for source in ["", ".", "./", "../elsewhere", "home/../..", "/etc"] {
+        dots.add("link_o_matic.toml", &format!("source = {:?}\n", source));
+        assert!(
+            matches!(dots.run(&["install"], ""), Err(Error::BadConfig { .. })),
+            "{:?} was accepted",
+            source
+        );
+    }
+    assert!(!dots.home(".link_o_matic.toml").exists());
+}
+
+#[test]
+fn linkomaticignore_files_skip_and_orphan_links() {
+    let dots = Dotfiles::new();
+    dots.add(".linkomaticignore", "README.md\n");
+    dots.add("home/config/.linkomaticignore", "*.swp\n!keep.swp\n");
+    dots.add("home/README.md", "docs");
+    dots.add("home/config/nvim/init.vim.swp", "swap");
+    let keep = dots.add("home/config/nvim/keep.swp", "keep");
+    dots.add("home/vimrc", "vim");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(!dots.home(".README.md").exists());
+    assert!(!dots.home(".config/.linkomaticignore").exists());
+    assert!(!dots.home(".config/nvim/init.vim.swp").exists());
+    assert!(points_to(&dots.home(".config/nvim/keep.swp"), &keep));
+    assert!(dots.home(".vimrc").is_symlink());
+
+    dots.add("home/.linkomaticignore", "vimrc\n");
+    dots.run(&["autocleanup"], "").unwrap();
+
+    assert!(!dots.home(".vimrc").is_symlink());
+    assert!(dots.home(".config/nvim/keep.swp").is_symlink());
+}
+
+#[test]
+fn folded_directories_are_linked_whole() {
+    let dots = Dotfiles::new();
+    dots.add("home/config/nvim/init.vim", "set nu");
+    dots.add("home/config/nvim/lua/plugins.lua", "return {}");
+    dots.run(&["install"], "").unwrap();
+    assert!(!dots.home(".config/nvim").is_symlink());
+
+    dots.add("home/config/nvim/.linkomatic-fold", "");
+    dots.run(&["install"], "").unwrap();
+    assert!(points_to(
+        &dots.home(".config/nvim"),
+        &dots.root.join("home/config/nvim")
+    ));
+
+    dots.add("home/config/nvim/lua/new.lua", "new");
+    assert!(dots.home(".config/nvim/lua/new.lua").exists());
+    dots.run(&["status"], "").unwrap();
+
+    remove_file(dots.root.join("home/config/nvim/.linkomatic-fold")).unwrap();
+    dots.run(&["autocleanup"], "").unwrap();
+    assert!(!dots.home(".config/nvim").exists());
+    dots.run(&["install"], "").unwrap();
+    assert!(dots.home(".config/nvim/lua/new.lua").is_symlink());
+}
+
+#[test]
+fn arch_and_tag_labels_select_files() {
+    let dots = Dotfiles::new();
+    let arch = dots.add(
+        &format!("home/config/fish/_{}.fish", std::env::consts::ARCH),
+        "arch",
+    );
+    let work = dots.add("home/config/fish/_work.fish", "work");
+    dots.add("home/config/fish/_personal.fish", "personal");
+
+    dots.run(&["--tags", "work,server", "install"], "").unwrap();
+
+    assert!(points_to(&dots.home(".config/fish/_arch.fish"), &arch));
+    assert!(points_to(&dots.home(".config/fish/_work.fish"), &work));
+    assert!(!dots.home(".config/fish/_personal.fish").exists());
+
+    dots.run(&["--tags", "personal", "autocleanup"], "")
+        .unwrap();
+    assert!(!dots.home(".config/fish/_work.fish").exists());
+    assert!(dots.home(".config/fish/_arch.fish").is_symlink());
+}
+
+#[test]
+fn labels_are_only_rewritten_at_the_start_of_the_file_name() {
+    let dots = Dotfiles::new();
+    let dir = format!("home/config/my_{}_stuff", platform());
+    let machine = dots.add(&format!("{}/_bob_{}.conf", dir, platform()), "bob");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(points_to(
+        &dots.home(&format!(
+            ".config/my_{}_stuff/_machine_{}.conf",
+            platform(),
+            platform()
+        )),
+        &machine
+    ));
+    dots.run(&["status"], "").unwrap();
+}
+
+#[test]
+fn default_files_fill_in_for_the_machine() {
+    let dots = Dotfiles::new();
+    let default = dots.add("home/config/fish/_default.fish", "default");
+    let platform = dots.add(
+        &format!("home/config/fish/_{}.fish", platform()),
+        "platform",
+    );
+    dots.add("home/config/nvim/_default.lua", "default");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert!(points_to(
+        &dots.home(".config/fish/_machine.fish"),
+        &platform
+    ));
+    assert!(points_to(
+        &dots.home(".config/fish/_platform.fish"),
+        &platform
+    ));
+    assert!(dots.home(".config/nvim/_machine.lua").is_symlink());
+
+    let machine = dots.add("home/config/fish/_bob.fish", "bob");
+    dots.run(&["--yes", "sync"], "").unwrap();
+    assert!(points_to(
+        &dots.home(".config/fish/_machine.fish"),
+        &machine
+    ));
+
+    remove_file(&machine).unwrap();
+    remove_file(&platform).unwrap();
+    dots.run(&["--yes", "sync"], "").unwrap();
+    assert!(points_to(
+        &dots.home(".config/fish/_machine.fish"),
+        &default
+    ));
+    assert!(!dots.home(".config/fish/_platform.fish").exists());
+}
+
+#[test]
+fn earlier_tags_win_over_later_ones() {
+    let dots = Dotfiles::new();
+    dots.add("home/config/_default.fish", "default");
+    let work = dots.add("home/config/_work.fish", "work");
+    let personal = dots.add("home/config/_personal.fish", "personal");
+    let home = dots.add("home/config/_home.fish", "home");
+    dots.add("home/config/_family.fish", "family");
+    dots.add(
+        "link_o_matic.toml",
+        "[labels]\nhome = [\"bob\"]\nfamily = [\"bob\"]\n",
+    );
+
+    dots.run(&["--tags", "work,personal", "install"], "")
+        .unwrap();
+    assert!(points_to(&dots.home(".config/_machine.fish"), &work));
+
+    dots.run(&["--yes", "--tags", "personal,work", "sync"], "")
+        .unwrap();
+    assert!(points_to(&dots.home(".config/_machine.fish"), &personal));
+
+    dots.run(&["--yes", "sync"], "").unwrap();
+    assert!(points_to(&dots.home(".config/_machine.fish"), &home));
+}
+
+#[test]
+fn sources_wanting_the_same_link_are_reported_before_linking() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "vim");
+    let linked = dots.add("home/config/fish/_bob.fish", "link");
+    let copied = dots.add("home/config/fish/_bob.fish.copy", "copy");
+
+    match dots.run(&["install"], "") {
+        Err(Error::Collisions(collisions)) => {
+            assert_eq!(
+                collisions,
+                vec![(
+                    dots.home(".config/fish/_machine.fish"),
+                    vec![linked, copied]
+                )]
+            );
+        }
+        other => panic!("expected collisions, got {:?}", other),
+    }
+
+    assert!(!dots.home(".vimrc").exists());
+}
+
+#[test]
+fn absolute_aliases_outside_the_root_are_opt_in() {
+    let dots = Dotfiles::new();
+    let init = dots.add("config/nvim/init.vim", "set nu");
+    symlink(&init, dots.root.join("home/vimrc")).unwrap();
+
+    let shared = dots.root.parent().unwrap().join("shared");
+    create_dir_all(&shared).unwrap();
+    write(shared.join("teamrc"), "team").unwrap();
+    symlink(shared.join("teamrc"), dots.root.join("home/teamrc")).unwrap();
+
+    dots.run(&["install"], "").unwrap();
+    assert!(points_to(&dots.home(".vimrc"), &init));
+    assert!(!dots.home(".teamrc").exists());
+
+    dots.add(
+        "link_o_matic.toml",
+        &format!("allow_outside = [\"{}\"]\n", shared.display()),
+    );
+    dots.run(&["install"], "").unwrap();
+    assert!(points_to(&dots.home(".teamrc"), &shared.join("teamrc")));
+
+    remove_file(dots.root.join("home/teamrc")).unwrap();
+    dots.run(&["autocleanup"], "").unwrap();
+    assert!(!dots.home(".teamrc").is_symlink());
+    assert!(shared.join("teamrc").exists());
+}
+
+#[test]
+fn relative_links_survive_moving_home_and_are_cleaned_up() {
+    let dots = Dotfiles::new();
+    dots.add("link_o_matic.toml", "relative_links = true\n");
+    let vimrc = dots.add("home/vimrc", "vim");
+    let gone = dots.add("home/config/fish/gone.fish", "gone");
+
+    dots.run(&["install"], "").unwrap();
+
+    assert_eq!(
+        read_link(dots.home(".vimrc")).unwrap(),
+        Path::new("../dotfiles/home/vimrc")
+    );
+    assert_eq!(read_to_string(dots.home(".vimrc")).unwrap(), "vim");
+
+    remove_file(&gone).unwrap();
+    dots.run(&["autocleanup"], "").unwrap();
+
+    assert!(!dots.home(".config/fish/gone.fish").is_symlink());
+    assert!(dots.home(".vimrc").is_symlink());
+    assert!(vimrc.exists());
+}
+
+#[test]
+fn backups_are_listed_and_restored() {
+    let dots = Dotfiles::new();
+    let vimrc = dots.add("home/vimrc", "from repo");
+    write(dots.home(".vimrc"), "first").unwrap();
+    dots.run(&["--yes", "install"], "").unwrap();
+
+    let backup = dots.backup(".vimrc").unwrap();
+    let run_id = backup
+        .parent()
+        .unwrap()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap();
+    dots.run(&["backups"], "").unwrap();
+
+    let vimrc_link = dots.home(".vimrc").display().to_string();
+    dots.run(&["backups", "restore", run_id, &vimrc_link], "")
+        .unwrap();
+    assert_eq!(read_to_string(dots.home(".vimrc")).unwrap(), "first");
+    assert!(dots.backup(".vimrc").is_none());
+
+    dots.run(&["undo"], "").unwrap();
+    assert!(points_to(&dots.home(".vimrc"), &vimrc));
+    assert_eq!(read_to_string(&backup).unwrap(), "first");
+}
+
+#[test]
+fn trash_flag_moves_deleted_things_to_the_trash() {
+    let dots = Dotfiles::new();
+    dots.add("home/vimrc", "from repo");
+    let gone = dots.add("home/config/gone/thing", "gone");
+    write(dots.home(".vimrc"), "hand edited").unwrap();
+
+    dots.run(&["--trash", "--yes", "install"], "").unwrap();
+    remove_file(&gone).unwrap();
+    dots.run(&["--trash", "autocleanup"], "").unwrap();
+
+    let trash = dots.home(".local/share/Trash");
+    assert_eq!(
+        read_to_string(trash.join("files/.vimrc")).unwrap(),
+        "hand edited"
+    );
+    assert!(trash.join("files/thing").is_symlink());
+    assert!(trash.join("files/gone").is_dir());
+    assert!(!dots.home(".config/gone").exists());
+
+    let info = read_to_string(trash.join("info/thing.trashinfo")).unwrap();
+    assert!(info.starts_with("[Trash Info]\n"));
+    assert!(info.contains(&format!(
+        "Path={}\n",
+        dots.home(".config/gone/thing").display()
+    )));
+
+    dots.run(&["undo"], "").unwrap();
+    assert!(dots.home(".config/gone/thing").is_symlink());
+    assert!(!trash.join("info/thing.trashinfo").exists());
+}
+
+#[test]
+fn real_directories_in_the_way_are_backed_up_or_merged() {
+    let dots = Dotfiles::new();
+    dots.add("home/config/foo/.linkomatic-fold", "");
+    let ours = dots.add("home/config/foo/settings", "repo");
+    dots.add("home/config/bar/.linkomatic-fold", "");
+    dots.add("home/config/bar/settings", "repo");
+
+    for dir in ["foo", "bar"] {
+        create_dir_all(dots.home(&format!(".config/{}/cache", dir))).unwrap();
+        write(dots.home(&format!(".config/{}/settings", dir)), "app").unwrap();
+        write(dots.home(&format!(".config/{}/cache/state", dir)), "app").unwrap();
+    }
+
+    dots.run(&["install"], "n\nn\n").unwrap();
+    assert!(!dots.home(".config/foo").is_symlink());
+
+    dots.run(&["install"], "m\ny\n").unwrap();
+
+    assert!(points_to(&dots.home(".config/foo"), ours.parent().unwrap()));
+    assert_eq!(
+        read_to_string(dots.backup(".config/foo/settings").unwrap()).unwrap(),
+        "app"
+    );
+
+    assert!(dots.home(".config/bar").is_symlink());
+    assert_eq!(
+        read_to_string(dots.root.join("home/config/bar/cache/state")).unwrap(),
+        "app"
+    );
+    assert_eq!(
+        read_to_string(dots.root.join("home/config/bar/settings")).unwrap(),
+        "repo"
+    );
+    assert_eq!(
+        read_to_string(dots.backup(".config/bar/settings").unwrap()).unwrap(),
+        "app"
+    );
+}
+
+#[test]
+fn real_files_in_the_way_can_be_pulled_into_the_repo() {
+    let dots = Dotfiles::new();
+    let bashrc = dots.add("home/bashrc", "repo");
+    let shared = dots.add("home/config/nvim/_default.lua", "shared");
+    write(dots.home(".bashrc"), "edited here").unwrap();
+    create_dir_all(dots.home(".config/nvim")).unwrap();
+    write(dots.home(".config/nvim/_machine.lua"), "only here").unwrap();
+
+    dots.run(&["install"], "p\np\n").unwrap();
+
+    assert!(points_to(&dots.home(".bashrc"), &bashrc));
+    assert_eq!(read_to_string(&bashrc).unwrap(), "edited here");
+
+    let own = dots.root.join("home/config/nvim/_bob.lua");
+    assert!(points_to(&dots.home(".config/nvim/_machine.lua"), &own));
+    assert_eq!(read_to_string(&own).unwrap(), "only here");
+    assert_eq!(read_to_string(&shared).unwrap(), "shared");
+
+    dots.run(&["undo"], "").unwrap();
+    assert_eq!(read_to_string(&bashrc).unwrap(), "repo");
+    assert_eq!(read_to_string(dots.home(".bashrc")).unwrap(), "edited here");
+    assert!(!own.exists());
+}
+
+#[test]
+fn diff_shows_how_a_real_file_differs_from_the_repo() {
+    let dots = Dotfiles::new();
+    let gitconfig = dots.add("home/gitconfig", "[user]\n  email = bob@example.com\n");
+    write(dots.home(".gitconfig"), "[user]\n  email = hand@edited\n").unwrap();
+
+    let output = dots.bin(&["--format", "json", "diff"]).output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{{\"level\":\"log\",\"verb\":\"diff\",\"path\":\".gitconfig\",\"target\":\"{}\",\"message\":\"--- {}\\n+++ {}\\n@@ -1,2 +1,2 @@\\n [user]\\n-  email = hand@edited\\n+  email = bob@example.com\\n\"}}\n",
+            gitconfig.display(),
+            dots.home(".gitconfig").display(),
+            gitconfig.display()
+        )
+    );
+
+    // looking at the diff isn't an answer
+    dots.run(&["install"], "d\nn\n").unwrap();
+    assert!(!dots.home(".gitconfig").is_symlink());
+    dots.run(&["install"], "d\ny\n").unwrap();
+    assert!(points_to(&dots.home(".gitconfig"), &gitconfig));
+}